@@ -1,25 +1,36 @@
 use crate::erspan::ErspanPacket;
+use crate::flow_expiration::FlowTimingWheel;
 use crate::flow_map::FlowMap;
+use crate::geneve::GenevePacket;
+use crate::ieee802154::{
+    self, Ieee802154Addr, SixLowPanDefrag, SixLowPanFragment,
+};
 use crate::ip_defrag::{DefragEngine, Fragment, IPDefragEngine};
 use crate::layers::LinkLayerType;
-use crate::packet_info::PacketInfo;
+use crate::output::{FlowExporter, OutputRecord};
+use crossbeam_channel::Sender;
+use crate::packet_info::{IcmpErrorReason, PacketInfo};
 use crate::plugin::*;
 use crate::plugin_registry::*;
+use crate::plugin_stats::{PluginID, PluginOutcome};
 use crate::ppp::{PppPacket, PppProtocolTypes};
+use crate::rewrite::{serialize_rewritten_layer, RewriteWriter, RewrittenLayer};
 use crate::pppoe::PppoeSessionPacket;
+use crate::tcp_reassembly::TcpStreamReassembly;
 use crate::vxlan::*;
 use libpcap_tools::*;
 
 use pcap_parser::{data::PacketData, Linktype};
 use std::cmp::min;
-use std::net::IpAddr;
+use std::net::{IpAddr, Ipv4Addr};
 use std::ops::DerefMut;
+use std::panic::{catch_unwind, AssertUnwindSafe};
 use std::sync::Arc;
 
 use pnet_packet::ethernet::{EtherType, EtherTypes, EthernetPacket};
 use pnet_packet::gre::GrePacket;
-use pnet_packet::icmp::IcmpPacket;
-use pnet_packet::icmpv6::Icmpv6Packet;
+use pnet_packet::icmp::{IcmpPacket, IcmpTypes};
+use pnet_packet::icmpv6::{Icmpv6Packet, Icmpv6Types};
 use pnet_packet::ip::{IpNextHeaderProtocol, IpNextHeaderProtocols};
 use pnet_packet::ipv4::{Ipv4Flags, Ipv4Packet};
 use pnet_packet::ipv6::{ExtensionPacket, FragmentPacket, Ipv6Packet};
@@ -28,11 +39,53 @@ use pnet_packet::udp::UdpPacket;
 use pnet_packet::vlan::VlanPacket;
 use pnet_packet::{Packet as PnetPacket, PacketSize};
 
+/// How `run_plugins_v2` reacts to a plugin returning `PluginResult::Error`,
+/// panicking, or locking a poisoned mutex (e.g. after a previous panic).
+/// Configured via the `plugin_error_policy` config key: `"continue"`
+/// (default), `"abort_flow"`, `"abort_run"`, or `"collect"`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum PluginErrorPolicy {
+    /// Log and move on to the next plugin, as before.
+    Continue,
+    /// Stop dispatching to further plugins for this layer/packet, but keep
+    /// processing the rest of the capture.
+    AbortFlow,
+    /// Propagate the error out of `handle_packet`, ending the run.
+    AbortRun,
+    /// Log, record `(PluginID, packet index, error)` into `Analyzer::plugin_errors`
+    /// for the report `teardown` prints, and move on.
+    Collect,
+}
+
+impl PluginErrorPolicy {
+    fn from_config(config: &Config) -> Self {
+        match config.get("plugin_error_policy") {
+            Some("abort_flow") => PluginErrorPolicy::AbortFlow,
+            Some("abort_run") => PluginErrorPolicy::AbortRun,
+            Some("collect") => PluginErrorPolicy::Collect,
+            Some("continue") | None => PluginErrorPolicy::Continue,
+            Some(other) => {
+                warn!("unknown plugin_error_policy '{}', defaulting to continue", other);
+                PluginErrorPolicy::Continue
+            }
+        }
+    }
+}
+
 #[derive(Clone, Debug, Default)]
 pub struct L3Info {
     /// Layer 4 protocol (e.g TCP, UDP, ICMP)
     pub l4_proto: u8,
     pub three_tuple: ThreeTuple,
+    /// Stacked VLAN ids the packet was seen on, outermost first (empty if
+    /// the packet was untagged, or `flow_include_vlan` is disabled).
+    pub vlan_tags: Vec<u16>,
+    /// GENEVE Virtual Network Identifier the packet was tunneled through,
+    /// if any. Unlike `vlan_tags`, there is no opt-in config flag: a VNI
+    /// is a hard tenant/virtual-network boundary (not something
+    /// intermediate network gear can add or strip, unlike a VLAN tag), so
+    /// it is always folded into the flow key once present.
+    pub vni: Option<u32>,
 }
 
 /// Pcap/Pcap-ng analyzer
@@ -46,34 +99,167 @@ pub struct L3Info {
 /// configuration variable. By default, it is 0 (auto-detect the number
 /// of cores and create the same number of threads).
 ///
+/// If `flow_include_vlan` is set, the stacked VLAN ids a packet travelled
+/// through (802.1Q and 802.1ad, including QinQ) are folded into the flow
+/// key, so the same IP/port tuple on two different VLANs is tracked as
+/// two distinct flows.
+///
 /// All callbacks for a single ISO layer will be called concurrently before
 /// calling the next level callbacks.
 pub struct Analyzer {
     pub(crate) registry: Arc<PluginRegistry>,
 
     pub(crate) flows: FlowMap,
+    /// Tracks flow idle time so `flow_destroyed` fires during the capture
+    /// instead of only at `teardown`; see `get_or_create_flow`.
+    flow_timing_wheel: FlowTimingWheel,
 
     ipv4_defrag: Box<dyn DefragEngine>,
     ipv6_defrag: Box<dyn DefragEngine>,
+    sixlowpan_defrag: SixLowPanDefrag,
+    pub(crate) tcp_reassembly: TcpStreamReassembly,
 
     do_checksums: bool,
+
+    /// If set, the VLAN tag stack is included in the `FiveTuple` used to
+    /// look up flows, so identical IP/port tuples on different VLANs are
+    /// tracked as distinct flows.
+    flow_include_vlan: bool,
+
+    /// Where `PluginResult::Rewrite` results are written, if a plugin wants
+    /// to emit edited packets. `None` (the default) when `rewrite_output_path`
+    /// isn't set, so plugins that never return `Rewrite` pay nothing.
+    rewrite_sink: Option<RewriteWriter>,
+
+    plugin_error_policy: PluginErrorPolicy,
+    /// `(PluginID, packet index, error message)` tuples recorded under the
+    /// `Collect` error policy, reported once at `teardown`.
+    plugin_errors: Vec<(PluginID, usize, String)>,
+
+    /// Exports flow records as NetFlow v9, if `netflow_output_path` or
+    /// `netflow_collector_addr` is configured; `None` otherwise, so flows
+    /// that are never exported pay nothing beyond the counters already
+    /// tracked on `Flow` itself.
+    flow_exporter: Option<FlowExporter>,
+    /// How long a still-open flow can go without being re-exported; see
+    /// `maybe_export_active_flow`.
+    flow_active_timeout: Duration,
+
+    /// Where destroyed flows are sent for persistence (e.g. to a
+    /// `SqliteSink`, via `output::RecordWriter`'s background thread), if
+    /// wired up with `set_record_sink`. Unlike `flow_exporter`, this isn't
+    /// populated from `Config` directly: a `RecordWriter` is a single
+    /// writer thread shared across every `ThreadedAnalyzer` worker, so it's
+    /// constructed once by the caller and its `Sender` cloned in here --
+    /// constructing one independently per worker from `Config` would give
+    /// each worker its own store/connection instead of fanning into one.
+    record_tx: Option<Sender<OutputRecord>>,
 }
 
 impl Analyzer {
     pub fn new(registry: Arc<PluginRegistry>, config: &Config) -> Analyzer {
         let do_checksums = config.get_bool("do_checksums").unwrap_or(true);
+        let flow_include_vlan = config.get_bool("flow_include_vlan").unwrap_or(false);
+        let rewrite_sink = config.get("rewrite_output_path").map(|path| {
+            RewriteWriter::new(path)
+                .unwrap_or_else(|e| panic!("could not create rewrite output {}: {}", path, e))
+        });
+        let flow_exporter = FlowExporter::from_config(config);
+        let flow_active_timeout_secs = config.get_usize("flow_active_timeout_secs").unwrap_or(1800) as u64;
         Analyzer {
             registry,
             flows: FlowMap::default(),
-            ipv4_defrag: Box::new(IPDefragEngine::new()),
-            ipv6_defrag: Box::new(IPDefragEngine::new()),
+            flow_timing_wheel: FlowTimingWheel::new(config),
+            ipv4_defrag: Box::new(IPDefragEngine::new_ipv4(config)),
+            ipv6_defrag: Box::new(IPDefragEngine::new_ipv6(config)),
+            sixlowpan_defrag: SixLowPanDefrag::default(),
+            tcp_reassembly: TcpStreamReassembly::default(),
             do_checksums,
+            flow_include_vlan,
+            rewrite_sink,
+            plugin_error_policy: PluginErrorPolicy::from_config(config),
+            plugin_errors: Vec::new(),
+            flow_exporter,
+            flow_active_timeout: Duration::new(flow_active_timeout_secs, 0),
+            record_tx: None,
+        }
+    }
+
+    /// Wires this analyzer's destroyed flows into a persistent-store
+    /// writer thread: every flow sent this way ends up in one call to
+    /// `RecordSink::write_batch` somewhere, batched alongside whatever any
+    /// other `Analyzer` sharing the same `RecordWriter` sends. Only ever
+    /// pushes onto a channel -- no I/O happens on this thread.
+    pub fn set_record_sink(&mut self, tx: Sender<OutputRecord>) {
+        self.record_tx = Some(tx);
+    }
+
+    /// Drops this analyzer's `Sender` clone, so a shared `RecordWriter`
+    /// sees one less outstanding sender. `ThreadedAnalyzer::teardown` calls
+    /// this on every worker before joining the writer thread, so its
+    /// channel actually closes instead of blocking on `recv` forever.
+    pub(crate) fn close_record_sink(&mut self) {
+        self.record_tx = None;
+    }
+
+    /// Re-serialize `layer` and append it to the rewrite output sink
+    /// (no-op, with a warning, if `rewrite_output_path` wasn't configured).
+    fn write_rewrite(&mut self, packet: &Packet, layer: &RewrittenLayer) {
+        match self.rewrite_sink.as_mut() {
+            Some(sink) => {
+                let bytes = serialize_rewritten_layer(layer);
+                if let Err(e) = sink.write_packet(packet.ts, &bytes) {
+                    warn!("failed to write rewritten packet: {}", e);
+                }
+            }
+            None => warn!("plugin returned PluginResult::Rewrite but rewrite_output_path is not configured"),
+        }
+    }
+
+    /// Apply `plugin_error_policy` to a plugin failure (`PluginResult::Error`,
+    /// a panic caught around the plugin call, or a poisoned mutex). Returns
+    /// `Ok(true)` to keep dispatching to the remaining plugins, `Ok(false)`
+    /// to stop dispatching for this layer/packet (`AbortFlow`), or `Err` to
+    /// abort the whole run (`AbortRun`), propagated by `?` out of
+    /// `run_plugins_v2` and up through `handle_packet`.
+    fn on_plugin_error(&mut self, id: PluginID, pcap_index: usize, msg: String) -> Result<bool, Error> {
+        match self.plugin_error_policy {
+            PluginErrorPolicy::Continue => {
+                warn!("plugin {:?} error on packet #{}: {}", id, pcap_index, msg);
+                Ok(true)
+            }
+            PluginErrorPolicy::AbortFlow => {
+                warn!(
+                    "plugin {:?} error on packet #{}, aborting remaining plugins for this packet: {}",
+                    id, pcap_index, msg
+                );
+                Ok(false)
+            }
+            PluginErrorPolicy::AbortRun => {
+                warn!("plugin {:?} error on packet #{}, aborting run: {}", id, pcap_index, msg);
+                Err(Error::Generic("plugin error, aborting run"))
+            }
+            PluginErrorPolicy::Collect => {
+                warn!("plugin {:?} error on packet #{} (collected): {}", id, pcap_index, msg);
+                self.plugin_errors.push((id, pcap_index, msg));
+                Ok(true)
+            }
         }
     }
 
     #[inline]
     fn handle_l2(&mut self, packet: &Packet, ctx: &ParseContext, data: &[u8]) -> Result<(), Error> {
-        handle_l2(packet, ctx, data, self)
+        handle_l2(packet, ctx, data, self, None)
+    }
+
+    #[inline]
+    fn handle_l2_ieee802154(
+        &mut self,
+        packet: &Packet,
+        ctx: &ParseContext,
+        data: &[u8],
+    ) -> Result<(), Error> {
+        handle_l2_ieee802154(packet, ctx, data, self)
     }
 }
 
@@ -82,6 +268,7 @@ pub(crate) fn handle_l2(
     ctx: &ParseContext,
     data: &[u8],
     analyzer: &mut Analyzer,
+    vni: Option<u32>,
 ) -> Result<(), Error> {
     trace!("handle_l2 (idx={})", ctx.pcap_index);
 
@@ -119,7 +306,7 @@ pub(crate) fn handle_l2(
             let payload = eth.payload();
             trace!("    ethertype: 0x{:x}", ethertype.0);
             run_plugins_v2_link(packet, ctx, LinkLayerType::Ethernet, payload, analyzer)?;
-            handle_l3(&packet, &ctx, payload, ethertype, analyzer)
+            handle_l3(&packet, &ctx, payload, ethertype, analyzer, Vec::new(), vni)
         }
         None => {
             // packet too small to be ethernet
@@ -128,12 +315,88 @@ pub(crate) fn handle_l2(
     }
 }
 
+/// Entry point for IEEE 802.15.4 link-layer frames (low-power mesh
+/// captures, e.g. Zigbee/Thread). Parses the MAC header, reassembles
+/// 6LoWPAN fragments if needed, decompresses the LOWPAN_IPHC payload into
+/// a full IPv6 datagram, and hands it to `handle_l3_ipv6`.
+pub(crate) fn handle_l2_ieee802154(
+    packet: &Packet,
+    ctx: &ParseContext,
+    data: &[u8],
+    analyzer: &mut Analyzer,
+) -> Result<(), Error> {
+    trace!("handle_l2_ieee802154 (idx={})", ctx.pcap_index);
+
+    run_plugins_v2_physical(packet, ctx, data, analyzer)?;
+
+    let (header, mac_payload) = match ieee802154::parse_mac_header(data) {
+        Some(v) => v,
+        None => {
+            warn!("IEEE 802.15.4: could not parse MAC header (idx={})", ctx.pcap_index);
+            return Ok(());
+        }
+    };
+    if !ieee802154::is_data_frame(&header) {
+        trace!("IEEE 802.15.4: non-data frame, ignoring");
+        return Ok(());
+    }
+
+    run_plugins_v2_link(packet, ctx, LinkLayerType::Ieee802154, mac_payload, analyzer)?;
+
+    let pan_id = header.src_pan_id.or(header.dst_pan_id).unwrap_or(0);
+    let src_key = match header.src_addr {
+        Ieee802154Addr::Extended(a) => a,
+        Ieee802154Addr::Short(a) => u64::from(a),
+        Ieee802154Addr::None => 0,
+    };
+
+    let compressed = match analyzer.sixlowpan_defrag.update(pan_id, src_key, mac_payload) {
+        SixLowPanFragment::NoFrag(d) => d.to_vec(),
+        SixLowPanFragment::Complete(v) => {
+            debug!("6LoWPAN reassembly complete, len={}", v.len());
+            v
+        }
+        SixLowPanFragment::Incomplete => {
+            trace!("6LoWPAN reassembly incomplete");
+            return Ok(());
+        }
+        SixLowPanFragment::Error => {
+            warn!("6LoWPAN reassembly error (idx={})", ctx.pcap_index);
+            return Ok(());
+        }
+    };
+
+    let ipv6_packet = match ieee802154::decompress_iphc(&compressed, header.src_addr, header.dst_addr)
+    {
+        Some(v) => v,
+        None => {
+            warn!(
+                "6LoWPAN: unsupported or truncated IPHC datagram (idx={})",
+                ctx.pcap_index
+            );
+            return Ok(());
+        }
+    };
+
+    handle_l3_ipv6(
+        packet,
+        ctx,
+        &ipv6_packet,
+        EtherTypes::Ipv6,
+        analyzer,
+        Vec::new(),
+        None,
+    )
+}
+
 pub(crate) fn handle_l3(
     packet: &Packet,
     ctx: &ParseContext,
     data: &[u8],
     ethertype: EtherType,
     analyzer: &mut Analyzer,
+    vlan_tags: Vec<u16>,
+    vni: Option<u32>,
 ) -> Result<(), Error> {
     if data.is_empty() {
         return Ok(());
@@ -141,15 +404,24 @@ pub(crate) fn handle_l3(
 
     // see https://www.iana.org/assignments/ieee-802-numbers/ieee-802-numbers.xhtml
     match ethertype {
-        EtherTypes::Ipv4 => handle_l3_ipv4(packet, ctx, data, ethertype, analyzer),
-        EtherTypes::Ipv6 => handle_l3_ipv6(packet, ctx, data, ethertype, analyzer),
-        EtherTypes::Vlan => handle_l3_vlan_801q(packet, ctx, data, ethertype, analyzer),
+        EtherTypes::Ipv4 => handle_l3_ipv4(packet, ctx, data, ethertype, analyzer, vlan_tags, vni),
+        EtherTypes::Ipv6 => handle_l3_ipv6(packet, ctx, data, ethertype, analyzer, vlan_tags, vni),
+        // 802.1Q C-TAG (also used for stacked inner tags)
+        EtherTypes::Vlan => {
+            handle_l3_vlan_tag(packet, ctx, data, analyzer, vlan_tags, vni, "802.1q")
+        }
+        // 802.1ad S-TAG (service provider tag), e.g. 88a8 -> 8100 -> 0800
+        EtherType(0x88a8) => {
+            handle_l3_vlan_tag(packet, ctx, data, analyzer, vlan_tags, vni, "802.1ad")
+        }
         // ignore ARP packets
         EtherTypes::Arp => Ok(()),
         // 0x880b: PPP (rfc7042)
-        EtherType(0x880b) => handle_l3_ppp(packet, ctx, data, ethertype, analyzer),
-        EtherType(0x88be) => handle_l3_erspan(packet, ctx, data, ethertype, analyzer),
-        EtherTypes::PppoeSession => handle_l3_pppoesession(packet, ctx, data, ethertype, analyzer),
+        EtherType(0x880b) => handle_l3_ppp(packet, ctx, data, ethertype, analyzer, vlan_tags, vni),
+        EtherType(0x88be) => handle_l3_erspan(packet, ctx, data, ethertype, analyzer, vlan_tags),
+        EtherTypes::PppoeSession => {
+            handle_l3_pppoesession(packet, ctx, data, ethertype, analyzer, vlan_tags, vni)
+        }
 
         e => {
             warn!(
@@ -167,6 +439,8 @@ fn handle_l3_ipv4(
     data: &[u8],
     ethertype: EtherType,
     analyzer: &mut Analyzer,
+    vlan_tags: Vec<u16>,
+    vni: Option<u32>,
 ) -> Result<(), Error> {
     trace!("handle_l3_ipv4 (idx={})", ctx.pcap_index);
     let ipv4 = Ipv4Packet::new(data).ok_or("Could not build IPv4 packet from data")?;
@@ -227,6 +501,7 @@ fn handle_l3_ipv4(
         frag_offset,
         more_fragments,
         payload,
+        packet.ts,
     );
     let payload = match defrag {
         Fragment::NoFrag(d) => {
@@ -250,10 +525,52 @@ fn handle_l3_ipv4(
     let l3_info = L3Info {
         three_tuple: t3,
         l4_proto,
+        vlan_tags,
+        vni,
     };
     handle_l3_common(packet, ctx, payload, &l3_info, analyzer)
 }
 
+/// Handle a pcap/pcap-ng block whose data already starts at the transport
+/// layer (`PacketData::L4`): raw TCP/UDP payloads, some proprietary
+/// linktypes, or re-injected data with no link/network header at all.
+///
+/// There is no real `ThreeTuple` to extract, so its fields are filled with
+/// explicit "unspecified" placeholders (`proto: 0`, unspecified IPv4
+/// addresses) rather than guessed, and flows built from it will only ever
+/// differ by port. `run_plugins_v2_network` still fires on this synthetic
+/// tuple so L3-filtered plugins see a (mostly empty) event, then
+/// `handle_l3_common` dispatches to the matching L4 handler exactly as it
+/// would for a datagram that arrived with a real IP header.
+fn handle_l4_only(
+    packet: &Packet,
+    ctx: &ParseContext,
+    l4_proto: u8,
+    data: &[u8],
+    analyzer: &mut Analyzer,
+) -> Result<(), Error> {
+    trace!("handle_l4_only (idx={})", ctx.pcap_index);
+    if data.is_empty() {
+        return Ok(());
+    }
+
+    let t3 = ThreeTuple {
+        proto: 0,
+        src: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+        dst: IpAddr::V4(Ipv4Addr::UNSPECIFIED),
+    };
+
+    run_plugins_v2_network(packet, ctx, data, &t3, l4_proto, analyzer)?;
+
+    let l3_info = L3Info {
+        three_tuple: t3,
+        l4_proto,
+        vlan_tags: Vec::new(),
+        vni: None,
+    };
+    handle_l3_common(packet, ctx, data, &l3_info, analyzer)
+}
+
 fn is_ipv6_opt(opt: IpNextHeaderProtocol) -> bool {
     match opt {
         IpNextHeaderProtocols::Hopopt
@@ -273,6 +590,8 @@ fn handle_l3_ipv6(
     data: &[u8],
     ethertype: EtherType,
     analyzer: &mut Analyzer,
+    vlan_tags: Vec<u16>,
+    vni: Option<u32>,
 ) -> Result<(), Error> {
     trace!("handle_l3_ipv6 (idx={})", ctx.pcap_index);
     let ipv6 = Ipv6Packet::new(data).ok_or("Could not build IPv6 packet from data")?;
@@ -338,6 +657,8 @@ fn handle_l3_ipv6(
     let l3_info = L3Info {
         three_tuple: t3,
         l4_proto: l4_proto.0,
+        vlan_tags,
+        vni,
     };
 
     if let Some(frag_info) = frag_ext {
@@ -349,19 +670,27 @@ fn handle_l3_ipv6(
     }
 }
 
-fn handle_l3_vlan_801q(
+/// Parse a single 802.1Q/802.1ad VLAN tag and recurse into the next layer,
+/// accumulating the VLAN id onto `vlan_tags` so stacked tags (QinQ, e.g.
+/// `88a8 -> 8100 -> 0800`) are fully unwound instead of being discarded
+/// after the first recursion.
+fn handle_l3_vlan_tag(
     packet: &Packet,
     ctx: &ParseContext,
     data: &[u8],
-    _ethertype: EtherType,
     analyzer: &mut Analyzer,
+    mut vlan_tags: Vec<u16>,
+    vni: Option<u32>,
+    tag_kind: &'static str,
 ) -> Result<(), Error> {
-    trace!("handle_l3_vlan_801q (idx={})", ctx.pcap_index);
-    let vlan = VlanPacket::new(data).ok_or("Could not build 802.1Q Vlan packet from data")?;
+    trace!("handle_l3_vlan_tag ({}) (idx={})", tag_kind, ctx.pcap_index);
+    let vlan = VlanPacket::new(data).ok_or("Could not build Vlan packet from data")?;
     let next_ethertype = vlan.get_ethertype();
-    trace!("    802.1q: VLAN id={}", vlan.get_vlan_identifier());
+    let vlan_id = vlan.get_vlan_identifier();
+    trace!("    {}: VLAN id={}", tag_kind, vlan_id);
 
-    handle_l3(&packet, &ctx, vlan.payload(), next_ethertype, analyzer)
+    vlan_tags.push(vlan_id);
+    handle_l3(&packet, &ctx, vlan.payload(), next_ethertype, analyzer, vlan_tags, vni)
 }
 
 fn handle_l3_erspan(
@@ -370,6 +699,7 @@ fn handle_l3_erspan(
     data: &[u8],
     _ethertype: EtherType,
     analyzer: &mut Analyzer,
+    _vlan_tags: Vec<u16>,
 ) -> Result<(), Error> {
     trace!("handle_l3_erspan (idx={})", ctx.pcap_index);
     let erspan = ErspanPacket::new(data).ok_or("Could not build Erspan packet from data")?;
@@ -378,7 +708,8 @@ fn handle_l3_erspan(
         erspan.get_vlan(),
         erspan.get_span_id()
     );
-    handle_l2(packet, ctx, erspan.payload(), analyzer)
+    // the mirrored frame is a fresh L2 frame: VLAN stack and VNI are reset
+    handle_l2(packet, ctx, erspan.payload(), analyzer, None)
 }
 
 fn handle_l3_pppoesession(
@@ -387,6 +718,8 @@ fn handle_l3_pppoesession(
     data: &[u8],
     ethertype: EtherType,
     analyzer: &mut Analyzer,
+    vlan_tags: Vec<u16>,
+    vni: Option<u32>,
 ) -> Result<(), Error> {
     trace!("handle_l3_pppoesession (idx={})", ctx.pcap_index);
     let session =
@@ -398,7 +731,7 @@ fn handle_l3_pppoesession(
         session.get_code(),
     );
     let ppp_data = session.payload();
-    handle_l3_ppp(packet, ctx, ppp_data, ethertype, analyzer)
+    handle_l3_ppp(packet, ctx, ppp_data, ethertype, analyzer, vlan_tags, vni)
 }
 
 fn handle_l3_ppp(
@@ -407,6 +740,8 @@ fn handle_l3_ppp(
     data: &[u8],
     ethertype: EtherType,
     analyzer: &mut Analyzer,
+    vlan_tags: Vec<u16>,
+    vni: Option<u32>,
 ) -> Result<(), Error> {
     trace!("handle_l3_ppp (idx={})", ctx.pcap_index);
     let ppp = PppPacket::new(data).ok_or("Could not build Ppp packet from data")?;
@@ -414,8 +749,12 @@ fn handle_l3_ppp(
     let payload = ppp.payload();
     trace!("    ppp: protocol=0x{:02x}", proto.0,);
     match proto {
-        PppProtocolTypes::Ipv4 => handle_l3_ipv4(packet, ctx, payload, ethertype, analyzer),
-        PppProtocolTypes::Ipv6 => handle_l3_ipv6(packet, ctx, payload, ethertype, analyzer),
+        PppProtocolTypes::Ipv4 => {
+            handle_l3_ipv4(packet, ctx, payload, ethertype, analyzer, vlan_tags, vni)
+        }
+        PppProtocolTypes::Ipv6 => {
+            handle_l3_ipv6(packet, ctx, payload, ethertype, analyzer, vlan_tags, vni)
+        }
         _ => {
             warn!("Unsupported PPP protocol 0x{:02x}", proto.0);
             Ok(())
@@ -437,8 +776,24 @@ fn handle_l3_common(
         IpNextHeaderProtocols::Icmpv6 => handle_l4_icmpv6(packet, ctx, data, &l3_info, analyzer),
         IpNextHeaderProtocols::Esp => handle_l4_generic(packet, ctx, data, &l3_info, analyzer),
         IpNextHeaderProtocols::Gre => handle_l4_gre(packet, ctx, data, &l3_info, analyzer),
-        IpNextHeaderProtocols::Ipv4 => handle_l3(packet, ctx, data, EtherTypes::Ipv4, analyzer),
-        IpNextHeaderProtocols::Ipv6 => handle_l3(packet, ctx, data, EtherTypes::Ipv6, analyzer),
+        IpNextHeaderProtocols::Ipv4 => handle_l3(
+            packet,
+            ctx,
+            data,
+            EtherTypes::Ipv4,
+            analyzer,
+            l3_info.vlan_tags.clone(),
+            l3_info.vni,
+        ),
+        IpNextHeaderProtocols::Ipv6 => handle_l3(
+            packet,
+            ctx,
+            data,
+            EtherTypes::Ipv6,
+            analyzer,
+            l3_info.vlan_tags.clone(),
+            l3_info.vni,
+        ),
         p => {
             warn!("Unsupported L4 proto {}", p);
             handle_l4_generic(packet, ctx, data, &l3_info, analyzer)
@@ -457,13 +812,30 @@ fn handle_l4_tcp(
     trace!("    l4_data len: {}", data.len());
     let tcp = TcpPacket::new(data).ok_or("Could not build TCP packet from data")?;
 
-    // XXX handle TCP defrag
     let l4_payload = Some(tcp.payload());
     let src_port = tcp.get_source();
     let dst_port = tcp.get_destination();
 
+    let five_tuple = build_five_tuple(analyzer, l3_info, src_port, dst_port);
+    let (_flow_id, flow) = get_or_create_flow(analyzer, &five_tuple, packet.ts);
+    let to_server = flow.five_tuple == five_tuple;
+
+    let stream_data = match analyzer.tcp_reassembly.update(&flow, &tcp, to_server) {
+        Ok(bytes) => bytes,
+        Err(e) => {
+            debug!("TCP reassembly: {:?} (idx={})", e, ctx.pcap_index);
+            Vec::new()
+        }
+    };
+    let stream_data = if stream_data.is_empty() {
+        None
+    } else {
+        Some(stream_data.as_slice())
+    };
+
     handle_l4_common(
-        packet, ctx, data, l3_info, src_port, dst_port, l4_payload, analyzer,
+        packet, ctx, data, l3_info, src_port, dst_port, l4_payload, stream_data, None,
+        Some(tcp.get_flags() as u8), analyzer,
     )
 }
 
@@ -487,9 +859,14 @@ fn handle_l4_udp(
     if src_port == 4789 || dst_port == 4789 {
         return handle_l4_vxlan(packet, ctx, data, l3_info, udp.payload(), analyzer);
     }
+    // if sport/dport == 6081, this could be GENEVE
+    // XXX l4 plugins will not be called
+    if src_port == 6081 || dst_port == 6081 {
+        return handle_l4_geneve(packet, ctx, data, l3_info, udp.payload(), analyzer);
+    }
 
     handle_l4_common(
-        packet, ctx, data, l3_info, src_port, dst_port, l4_payload, analyzer,
+        packet, ctx, data, l3_info, src_port, dst_port, l4_payload, None, None, None, analyzer,
     )
 }
 
@@ -519,8 +896,11 @@ fn handle_l4_icmp(
         }
     }
 
+    let related = classify_icmpv4_error(icmp.get_icmp_type(), icmp.get_icmp_code())
+        .and_then(|reason| correlate_icmpv4_error(analyzer, l3_info, icmp.payload(), reason));
+
     handle_l4_common(
-        packet, ctx, data, l3_info, src_port, dst_port, l4_payload, analyzer,
+        packet, ctx, data, l3_info, src_port, dst_port, l4_payload, None, related, None, analyzer,
     )
 }
 
@@ -550,8 +930,11 @@ fn handle_l4_icmpv6(
         }
     }
 
+    let related = classify_icmpv6_error(icmpv6.get_icmpv6_type())
+        .and_then(|reason| correlate_icmpv6_error(analyzer, l3_info, icmpv6.payload(), reason));
+
     handle_l4_common(
-        packet, ctx, data, l3_info, src_port, dst_port, l4_payload, analyzer,
+        packet, ctx, data, l3_info, src_port, dst_port, l4_payload, None, related, None, analyzer,
     )
 }
 
@@ -559,7 +942,7 @@ fn handle_l4_gre(
     packet: &Packet,
     ctx: &ParseContext,
     data: &[u8],
-    _l3_info: &L3Info,
+    l3_info: &L3Info,
     analyzer: &mut Analyzer,
 ) -> Result<(), Error> {
     trace!("handle_l4_gre (idx={})", ctx.pcap_index);
@@ -591,7 +974,15 @@ fn handle_l4_gre(
     };
     trace!("GRE: type=0x{:x}", next_proto);
 
-    handle_l3(packet, ctx, data, EtherType(next_proto), analyzer)
+    handle_l3(
+        packet,
+        ctx,
+        data,
+        EtherType(next_proto),
+        analyzer,
+        l3_info.vlan_tags.clone(),
+        l3_info.vni,
+    )
 }
 
 fn handle_l4_vxlan(
@@ -608,7 +999,47 @@ fn handle_l4_vxlan(
 
     trace!("    Vxlan: VLAN id={}", vxlan.get_vlan_identifier());
 
-    handle_l2(packet, ctx, payload, analyzer)
+    handle_l2(packet, ctx, payload, analyzer, None)
+}
+
+/// Decapsulate a GENEVE (RFC 8926) packet and dissect its payload,
+/// threading the tunnel's Virtual Network Identifier into the inner
+/// packet's `L3Info` the same way `vlan_tags` is threaded through VLAN
+/// tags, so `flow_map` keys flows per virtual network rather than
+/// collapsing identical inner IP/port tuples from different VNIs into
+/// one flow.
+fn handle_l4_geneve(
+    packet: &Packet,
+    ctx: &ParseContext,
+    _data: &[u8],
+    l3_info: &L3Info,
+    l4_data: &[u8],
+    analyzer: &mut Analyzer,
+) -> Result<(), Error> {
+    trace!("handle_l4_geneve (idx={})", ctx.pcap_index);
+    let geneve = GenevePacket::new(l4_data).ok_or("Could not build Geneve packet from data")?;
+    let payload = geneve.payload();
+    let vni = Some(geneve.get_vni());
+    trace!(
+        "    Geneve: VNI={} protocol_type=0x{:04x}",
+        geneve.get_vni(),
+        geneve.get_protocol_type()
+    );
+
+    match geneve.get_protocol_type() {
+        // Transparent Ethernet Bridging: the common case (an encapsulated
+        // Ethernet frame), the same as VXLAN always carries.
+        0x6558 => handle_l2(packet, ctx, payload, analyzer, vni),
+        other => handle_l3(
+            packet,
+            ctx,
+            payload,
+            EtherType(other),
+            analyzer,
+            l3_info.vlan_tags.clone(),
+            vni,
+        ),
+    }
 }
 
 fn handle_l4_ipv6frag(
@@ -636,7 +1067,7 @@ fn handle_l4_ipv6frag(
         let more_fragments = !last_fragment;
         analyzer
             .ipv6_defrag
-            .update(frag_id, frag_offset, more_fragments, data)
+            .update(frag_id, frag_offset, more_fragments, data, packet.ts)
     };
     let data = match defrag {
         Fragment::NoFrag(d) => d,
@@ -686,55 +1117,289 @@ fn handle_l4_generic(
     let dst_port = 0;
 
     handle_l4_common(
-        packet, ctx, data, l3_info, src_port, dst_port, l4_payload, analyzer,
+        packet, ctx, data, l3_info, src_port, dst_port, l4_payload, None, None, None, analyzer,
     )
 }
 
-#[allow(clippy::too_many_arguments)]
-fn handle_l4_common(
-    packet: &Packet,
-    ctx: &ParseContext,
-    l4_data: &[u8],
-    l3_info: &L3Info,
-    src_port: u16,
-    dst_port: u16,
-    l4_payload: Option<&[u8]>,
-    analyzer: &mut Analyzer,
-) -> Result<(), Error> {
-    let five_tuple =
-        FiveTuple::from_three_tuple(&l3_info.three_tuple, src_port, dst_port, l3_info.l4_proto);
-    trace!("5t: {}", five_tuple);
-    let now = packet.ts;
+/// Build the `FiveTuple` used as the flow key for this packet, folding in
+/// the VLAN tag stack when `flow_include_vlan` is enabled, and the GENEVE
+/// VNI whenever the packet arrived through a GENEVE tunnel.
+fn build_five_tuple(analyzer: &Analyzer, l3_info: &L3Info, src_port: u16, dst_port: u16) -> FiveTuple {
+    let five_tuple = if analyzer.flow_include_vlan {
+        FiveTuple::from_three_tuple_vlan(
+            &l3_info.three_tuple,
+            src_port,
+            dst_port,
+            l3_info.l4_proto,
+            l3_info.vlan_tags.clone(),
+        )
+    } else {
+        FiveTuple::from_three_tuple(&l3_info.three_tuple, src_port, dst_port, l3_info.l4_proto)
+    };
+    FiveTuple {
+        vni: l3_info.vni,
+        ..five_tuple
+    }
+}
 
+/// Look up the flow matching `five_tuple`, creating it (and firing the
+/// `PLUGIN_FLOW_NEW` event) if this is the first packet seen for it, then
+/// update its `last_seen`. Doesn't clone the flow; use this over
+/// `get_or_create_flow` when the caller doesn't need a snapshot of it (e.g.
+/// because it's about to mutate the real entry and clone afterwards).
+fn get_or_create_flow_id(analyzer: &mut Analyzer, five_tuple: &FiveTuple, now: Duration) -> FlowID {
     let flow_id = {
-        // flows modification section
         let flows = &mut analyzer.flows;
-        // lookup flow
-        let flow_id = match flows.lookup_flow(&five_tuple) {
+        let flow_id = match flows.lookup_flow(five_tuple) {
             Some(id) => id,
             None => {
-                let flow = Flow::new(&five_tuple, packet.ts.secs, packet.ts.micros);
+                let flow = Flow::new(five_tuple, now.secs, now.micros);
                 gen_event_new_flow(&flow, &analyzer.registry);
                 flows.insert_flow(five_tuple.clone(), flow)
             }
         };
-
-        // update flow
         flows.entry(flow_id).and_modify(|flow| {
             flow.flow_id = flow_id;
             flow.last_seen = now;
         });
         flow_id
     };
+    analyzer.flow_timing_wheel.track(flow_id, now);
+    sweep_expired_flows(analyzer, now);
+    flow_id
+}
 
-    // get a read-only reference to flow
+/// Look up the flow matching `five_tuple`, creating it (and firing the
+/// `PLUGIN_FLOW_NEW` event) if this is the first packet seen for it, then
+/// update its `last_seen` and return a snapshot of it.
+fn get_or_create_flow(analyzer: &mut Analyzer, five_tuple: &FiveTuple, now: Duration) -> (FlowID, Flow) {
+    let flow_id = get_or_create_flow_id(analyzer, five_tuple, now);
+    // clone because callers need to release the borrow on `analyzer.flows`
     let flow = analyzer
         .flows
         .get_flow(flow_id)
         .expect("could not get flow from ID")
-        .clone(); // clone because run_plugins_v2_transport borrows analyzer
+        .clone();
+    (flow_id, flow)
+}
 
-    let to_server = flow.five_tuple == five_tuple;
+/// Advance the idle-flow timing wheel to `now` and destroy whatever
+/// flows it reports as due, mirroring what `teardown` does for the
+/// flows still present at the end of the capture.
+fn sweep_expired_flows(analyzer: &mut Analyzer, now: Duration) {
+    let due = analyzer.flow_timing_wheel.advance(now);
+    for flow_id in due {
+        let flow = match analyzer.flows.get_flow(flow_id) {
+            Some(f) => f.clone(),
+            None => continue,
+        };
+        // Quantization in the wheel's bucket width can surface a flow
+        // slightly earlier than its exact timeout; re-check before
+        // actually destroying it.
+        if now.secs.saturating_sub(flow.last_seen.secs) < analyzer.flow_timing_wheel.timeout().secs {
+            analyzer.flow_timing_wheel.track(flow_id, flow.last_seen);
+            continue;
+        }
+        trace!("flow {:x} expired (idle timeout)", flow_id);
+        analyzer.registry.run_plugins(
+            "flow_destroyed",
+            |p| p.plugin_type() & PLUGIN_FLOW_DEL != 0,
+            |p| p.flow_destroyed(&flow),
+        );
+        if let Some(exporter) = analyzer.flow_exporter.as_mut() {
+            exporter.export_flow(&flow);
+            exporter.forget(flow_id);
+        }
+        if let Some(tx) = analyzer.record_tx.as_ref() {
+            let _ = tx.send(OutputRecord::Flow(flow));
+        }
+        analyzer.flows.remove_flow(flow_id);
+    }
+}
+
+/// Re-export a still-open flow's cumulative counters if it's been at least
+/// `flow_active_timeout` since it was last exported, so a collector doesn't
+/// have to wait for a long-lived flow to end (or the capture to finish) to
+/// see its traffic. No-op if `netflow_output_path`/`netflow_collector_addr`
+/// wasn't configured.
+fn maybe_export_active_flow(analyzer: &mut Analyzer, flow_id: FlowID, now: Duration) {
+    if analyzer.flow_exporter.is_none() {
+        return;
+    }
+    let active_timeout = analyzer.flow_active_timeout;
+    let flow = match analyzer.flows.get_flow(flow_id) {
+        Some(f) => f.clone(),
+        None => return,
+    };
+    let exporter = analyzer.flow_exporter.as_mut().expect("checked above");
+    if !exporter.active_export_due(flow_id, &flow, now, active_timeout) {
+        return;
+    }
+    exporter.export_flow(&flow);
+    exporter.mark_active_export(flow_id, now);
+}
+
+/// Classify an ICMPv4 message as an error worth correlating back to the
+/// flow it references, or `None` for informational types (echo, etc.).
+fn classify_icmpv4_error(
+    icmp_type: pnet_packet::icmp::IcmpType,
+    icmp_code: pnet_packet::icmp::IcmpCode,
+) -> Option<IcmpErrorReason> {
+    match icmp_type {
+        IcmpTypes::DestinationUnreachable if icmp_code.0 == 4 => {
+            Some(IcmpErrorReason::FragmentationNeeded)
+        }
+        IcmpTypes::DestinationUnreachable => Some(IcmpErrorReason::DestinationUnreachable),
+        IcmpTypes::TimeExceeded => Some(IcmpErrorReason::TimeExceeded),
+        _ => None,
+    }
+}
+
+/// Classify an ICMPv6 message as an error worth correlating back to the
+/// flow it references, or `None` for informational types.
+fn classify_icmpv6_error(
+    icmpv6_type: pnet_packet::icmpv6::Icmpv6Type,
+) -> Option<IcmpErrorReason> {
+    match icmpv6_type {
+        Icmpv6Types::DestinationUnreachable => Some(IcmpErrorReason::DestinationUnreachable),
+        Icmpv6Types::PacketTooBig => Some(IcmpErrorReason::FragmentationNeeded),
+        Icmpv6Types::TimeExceeded => Some(IcmpErrorReason::TimeExceeded),
+        _ => None,
+    }
+}
+
+/// Extract the source/destination port from the first 4 bytes following a
+/// quoted IP header, if the quoted L4 protocol carries ports (TCP/UDP).
+/// RFC 792/4443 only guarantee the first 8 bytes of the original L4
+/// header are present, which is enough for this.
+fn quoted_l4_ports(proto: IpNextHeaderProtocol, l4: &[u8]) -> (u16, u16) {
+    match proto {
+        IpNextHeaderProtocols::Tcp | IpNextHeaderProtocols::Udp if l4.len() >= 4 => (
+            u16::from_be_bytes([l4[0], l4[1]]),
+            u16::from_be_bytes([l4[2], l4[3]]),
+        ),
+        _ => (0, 0),
+    }
+}
+
+/// Parse the IPv4 header + first 8 bytes of L4 quoted in an ICMPv4 error
+/// payload, and look up the flow it belongs to. The quoted datagram is
+/// the original packet, so its own src/dst already give the right
+/// direction (no reversal needed, unlike the ICMP error's own addresses).
+fn correlate_icmpv4_error(
+    analyzer: &Analyzer,
+    l3_info: &L3Info,
+    payload: &[u8],
+    reason: IcmpErrorReason,
+) -> Option<(FlowID, IcmpErrorReason)> {
+    let inner = Ipv4Packet::new(payload)?;
+    let inner_proto = inner.get_next_level_protocol();
+    let (src_port, dst_port) = quoted_l4_ports(inner_proto, inner.payload());
+    let three_tuple = ThreeTuple {
+        proto: EtherTypes::Ipv4.0,
+        src: IpAddr::V4(inner.get_source()),
+        dst: IpAddr::V4(inner.get_destination()),
+    };
+    let five_tuple = if analyzer.flow_include_vlan {
+        FiveTuple::from_three_tuple_vlan(
+            &three_tuple,
+            src_port,
+            dst_port,
+            inner_proto.0,
+            l3_info.vlan_tags.clone(),
+        )
+    } else {
+        FiveTuple::from_three_tuple(&three_tuple, src_port, dst_port, inner_proto.0)
+    };
+    let five_tuple = FiveTuple {
+        vni: l3_info.vni,
+        ..five_tuple
+    };
+    let flow_id = analyzer.flows.lookup_flow(&five_tuple)?;
+    Some((flow_id, reason))
+}
+
+/// Same as `correlate_icmpv4_error`, for the 40-byte IPv6 header quoted in
+/// an ICMPv6 error payload.
+fn correlate_icmpv6_error(
+    analyzer: &Analyzer,
+    l3_info: &L3Info,
+    payload: &[u8],
+    reason: IcmpErrorReason,
+) -> Option<(FlowID, IcmpErrorReason)> {
+    let inner = Ipv6Packet::new(payload)?;
+    let inner_proto = inner.get_next_header();
+    let (src_port, dst_port) = quoted_l4_ports(inner_proto, inner.payload());
+    let three_tuple = ThreeTuple {
+        proto: EtherTypes::Ipv6.0,
+        src: IpAddr::V6(inner.get_source()),
+        dst: IpAddr::V6(inner.get_destination()),
+    };
+    let five_tuple = if analyzer.flow_include_vlan {
+        FiveTuple::from_three_tuple_vlan(
+            &three_tuple,
+            src_port,
+            dst_port,
+            inner_proto.0,
+            l3_info.vlan_tags.clone(),
+        )
+    } else {
+        FiveTuple::from_three_tuple(&three_tuple, src_port, dst_port, inner_proto.0)
+    };
+    let five_tuple = FiveTuple {
+        vni: l3_info.vni,
+        ..five_tuple
+    };
+    let flow_id = analyzer.flows.lookup_flow(&five_tuple)?;
+    Some((flow_id, reason))
+}
+
+#[allow(clippy::too_many_arguments)]
+fn handle_l4_common(
+    packet: &Packet,
+    ctx: &ParseContext,
+    l4_data: &[u8],
+    l3_info: &L3Info,
+    src_port: u16,
+    dst_port: u16,
+    l4_payload: Option<&[u8]>,
+    stream_data: Option<&[u8]>,
+    icmp_error: Option<(FlowID, IcmpErrorReason)>,
+    tcp_flags: Option<u8>,
+    analyzer: &mut Analyzer,
+) -> Result<(), Error> {
+    let five_tuple = build_five_tuple(analyzer, l3_info, src_port, dst_port);
+    trace!("5t: {}", five_tuple);
+
+    let flow_id = get_or_create_flow_id(analyzer, &five_tuple, packet.ts);
+    // `record_packet` doesn't change `five_tuple`, so this direction check
+    // is safe to make against the not-yet-updated entry -- no need for a
+    // full clone just to read one field.
+    let to_server = analyzer
+        .flows
+        .get_flow(flow_id)
+        .expect("could not get flow from ID")
+        .five_tuple
+        == five_tuple;
+    analyzer
+        .flows
+        .entry(flow_id)
+        .and_modify(|f| f.record_packet(to_server, l4_data.len() as u64, tcp_flags));
+    maybe_export_active_flow(analyzer, flow_id, packet.ts);
+
+    // Clone only once, after `record_packet`, so plugins below see this
+    // packet's counters/flags rather than the previous packet's.
+    let flow = analyzer
+        .flows
+        .get_flow(flow_id)
+        .expect("could not get flow from ID")
+        .clone();
+
+    if let Some((related_flow_id, reason)) = icmp_error {
+        if let Some(related_flow) = analyzer.flows.get_flow(related_flow_id) {
+            gen_event_icmp_error(related_flow, reason, &analyzer.registry);
+        }
+    }
 
     let pinfo = PacketInfo {
         five_tuple: &five_tuple,
@@ -745,6 +1410,9 @@ fn handle_l4_common(
         l4_payload,
         flow: Some(&flow),
         pcap_index: ctx.pcap_index,
+        stream_data,
+        related_flow_id: icmp_error.map(|(id, _)| id),
+        icmp_error_reason: icmp_error.map(|(_, reason)| reason),
     };
     // let start = ::std::time::Instant::now();
     run_plugins_v2_transport(packet, ctx, &pinfo, analyzer)?;
@@ -768,22 +1436,28 @@ fn handle_l4_common(
     Ok(())
 }
 
+#[allow(clippy::too_many_arguments)]
 fn run_plugins_v2<'i, F>(
     packet: &Packet,
     ctx: &ParseContext,
+    kind: &'static str,
     layer: u8,
     layer_filter: u16,
     cb: F,
     analyzer: &mut Analyzer,
 ) -> Result<(), Error>
 where
-    F: for<'p> Fn(&'p mut dyn Plugin) -> PluginResult<'i>,
+    F: for<'p> Fn(&'p mut dyn Plugin, &mut PluginContext) -> PluginResult<'i>,
 {
     trace!(
         "running plugins for layer={} filter=0x{:04x}",
         layer,
         layer_filter
     );
+    // Shared across every plugin dispatched below, in dependency order, so
+    // a plugin can read what an upstream dependency (see `Plugin::
+    // dependencies`) already published for this packet.
+    let mut pctx = PluginContext::default();
     // clone the registry (which is an Arc)
     // so analyzer is not borrowed for the plugins loop
     let registry = analyzer.registry.clone();
@@ -798,21 +1472,57 @@ where
         .get_plugins_for_layer(layer, 0)
         .unwrap_or(&empty_vec)
         .as_slice();
-    for plugin in l1.iter().chain(l2) {
-        let r = {
-            // limit duration of lock to vallback
-            let mut p = plugin.lock().expect("locking plugin failed (recursion ?)");
-            cb(p.deref_mut())
+    for (id, plugin) in l1.iter().chain(l2) {
+        // A poisoned mutex means a previous plugin call panicked while
+        // holding it; still reachable via `into_inner`, but that failure is
+        // routed through the same error policy as a fresh panic or
+        // `PluginResult::Error`, rather than `.expect`-ing into a crash.
+        let mut p = match plugin.lock() {
+            Ok(p) => p,
+            Err(poisoned) => {
+                if !analyzer.on_plugin_error(
+                    *id,
+                    packet.pcap_index,
+                    "plugin mutex poisoned (previous panic?)".to_string(),
+                )? {
+                    break;
+                }
+                poisoned.into_inner()
+            }
+        };
+        let start = registry.stats_start();
+        let unwind_result =
+            catch_unwind(AssertUnwindSafe(|| cb(p.deref_mut(), &mut pctx)));
+        drop(p);
+        let r = match unwind_result {
+            Ok(r) => r,
+            Err(payload) => {
+                registry.stats_record(*id, start, kind, PluginOutcome::Error);
+                if !analyzer.on_plugin_error(*id, packet.pcap_index, panic_message(&payload))? {
+                    break;
+                }
+                continue;
+            }
+        };
+        let outcome = match &r {
+            PluginResult::None => PluginOutcome::None,
+            PluginResult::Error(_) => PluginOutcome::Error,
+            PluginResult::L2(_, _) => PluginOutcome::L2,
+            PluginResult::L3(_, _) => PluginOutcome::L3,
+            PluginResult::L4(_, _) => PluginOutcome::L4,
+            PluginResult::Rewrite(_) => PluginOutcome::Rewrite,
         };
+        registry.stats_record(*id, start, kind, outcome);
         match r {
             PluginResult::None => continue,
             PluginResult::Error(e) => {
-                // XXX ignore error in plugins ? just log ?
-                warn!("Plugin returned error {:?}", e);
+                if !analyzer.on_plugin_error(*id, packet.pcap_index, format!("{:?}", e))? {
+                    break;
+                }
                 continue;
             }
             PluginResult::L2(e, payload) => {
-                handle_l3(packet, ctx, payload, EtherType(e), analyzer)?
+                handle_l3(packet, ctx, payload, EtherType(e), analyzer, Vec::new(), None)?
             }
             PluginResult::L3(l3, payload) => handle_l3_common(packet, ctx, payload, &l3, analyzer)?,
             PluginResult::L4(t5, payload) => {
@@ -825,14 +1535,30 @@ where
                     t5.src_port,
                     t5.dst_port,
                     Some(payload),
+                    None,
+                    None,
+                    None,
                     analyzer,
                 )?;
             }
+            PluginResult::Rewrite(layer) => analyzer.write_rewrite(packet, &layer),
         }
     }
     Ok(())
 }
 
+/// Best-effort extraction of a message from a caught panic payload, for the
+/// `Collect`/logged error policies.
+fn panic_message(payload: &(dyn std::any::Any + Send)) -> String {
+    if let Some(s) = payload.downcast_ref::<&str>() {
+        (*s).to_string()
+    } else if let Some(s) = payload.downcast_ref::<String>() {
+        s.clone()
+    } else {
+        "plugin panicked".to_string()
+    }
+}
+
 /// Run plugins attached to the physical layer
 pub(crate) fn run_plugins_v2_physical<'a>(
     packet: &Packet,
@@ -840,10 +1566,10 @@ pub(crate) fn run_plugins_v2_physical<'a>(
     data: &'a [u8],
     analyzer: &mut Analyzer,
 ) -> Result<(), Error> {
-    let cb = move |p: &mut dyn Plugin| p.handle_layer_physical(packet, data);
+    let cb = move |p: &mut dyn Plugin, pctx: &mut PluginContext| p.handle_layer_physical(packet, data, pctx);
     let layer = 1;
     let layer_filter = 0;
-    run_plugins_v2(packet, ctx, layer, layer_filter, cb, analyzer)
+    run_plugins_v2(packet, ctx, "physical", layer, layer_filter, cb, analyzer)
 }
 
 /// Run plugins attached to the link layer (ethernet, etc.)
@@ -854,10 +1580,12 @@ pub(crate) fn run_plugins_v2_link<'a>(
     l2_payload: &'a [u8],
     analyzer: &mut Analyzer,
 ) -> Result<(), Error> {
-    let cb = move |p: &mut dyn Plugin| p.handle_layer_link(packet, linktype as u16, l2_payload);
+    let cb = move |p: &mut dyn Plugin, pctx: &mut PluginContext| {
+        p.handle_layer_link(packet, linktype as u16, l2_payload, pctx)
+    };
     let layer = 2;
     let layer_filter = linktype as u16;
-    run_plugins_v2(packet, ctx, layer, layer_filter, cb, analyzer)
+    run_plugins_v2(packet, ctx, "link", layer, layer_filter, cb, analyzer)
 }
 
 /// Run plugins attached to the network layer (IPv4, IPv6, Arp, IPsec, etc.)
@@ -869,11 +1597,12 @@ fn run_plugins_v2_network<'a>(
     l4_proto: u8,
     analyzer: &mut Analyzer,
 ) -> Result<(), Error> {
-    let cb =
-        move |p: &mut dyn Plugin| p.handle_layer_network(packet, l3_payload, three_tuple, l4_proto);
+    let cb = move |p: &mut dyn Plugin, pctx: &mut PluginContext| {
+        p.handle_layer_network(packet, l3_payload, three_tuple, l4_proto, pctx)
+    };
     let layer = 3;
     let layer_filter = three_tuple.proto;
-    run_plugins_v2(packet, ctx, layer, layer_filter, cb, analyzer)
+    run_plugins_v2(packet, ctx, "network", layer, layer_filter, cb, analyzer)
 }
 
 /// Run plugins attached to the transport layer (TCP, UDP, etc.)
@@ -883,26 +1612,39 @@ fn run_plugins_v2_transport(
     pinfo: &PacketInfo,
     analyzer: &mut Analyzer,
 ) -> Result<(), Error> {
-    let cb = move |p: &mut dyn Plugin| p.handle_layer_transport(packet, pinfo);
+    let cb = move |p: &mut dyn Plugin, pctx: &mut PluginContext| {
+        p.handle_layer_transport(packet, pinfo, pctx)
+    };
     let layer = 4;
     let layer_filter = pinfo.l4_type as u16;
-    run_plugins_v2(packet, ctx, layer, layer_filter, cb, analyzer)
+    run_plugins_v2(packet, ctx, "transport", layer, layer_filter, cb, analyzer)
 }
 
 pub(crate) fn gen_event_new_flow(flow: &Flow, registry: &PluginRegistry) {
-    // let start = ::std::time::Instant::now();
     registry.run_plugins(
+        "flow_created",
         |p| p.plugin_type() & PLUGIN_FLOW_NEW != 0,
         |p| p.flow_created(flow),
     );
-    // let elapsed = start.elapsed();
-    // debug!("Time to run flow_created: {}.{}", elapsed.as_secs(), elapsed.as_millis());
+}
+
+/// Fired when an ICMP/ICMPv6 error message quotes a datagram belonging to
+/// a tracked flow (e.g. Path-MTU-Discovery probes, unreachable
+/// diagnostics), so plugins watching that flow learn about it even though
+/// the error itself arrives as part of a different flow.
+pub(crate) fn gen_event_icmp_error(flow: &Flow, reason: IcmpErrorReason, registry: &PluginRegistry) {
+    registry.run_plugins(
+        "flow_icmp_error",
+        |p| p.plugin_type() & PLUGIN_FLOW_ICMP_ERROR != 0,
+        |p| p.flow_icmp_error(flow, reason),
+    );
 }
 
 impl PcapAnalyzer for Analyzer {
     /// Initialize all plugins
     fn init(&mut self) -> Result<(), Error> {
-        self.registry.run_plugins(|_| true, |p| p.pre_process());
+        self.registry
+            .run_plugins("pre_process", |_| true, |p| p.pre_process());
         Ok(())
     }
 
@@ -910,11 +1652,32 @@ impl PcapAnalyzer for Analyzer {
     /// call the matching handling function (some pcap blocks encode ethernet, or IPv4 etc.)
     fn handle_packet(&mut self, packet: &Packet, ctx: &ParseContext) -> Result<(), Error> {
         match packet.data {
-            PacketData::L2(data) => self.handle_l2(packet, &ctx, data),
+            PacketData::L2(data) => match packet.linktype {
+                // `parse_mac_header` assumes the MAC footer/FCS is already
+                // stripped, which is only actually true of NOFCS captures.
+                Linktype::IEEE802_15_4_NOFCS => self.handle_l2_ieee802154(packet, &ctx, data),
+                // Plain IEEE802_15_4 keeps a trailing 2-byte FCS; strip it
+                // before handing off so it isn't mistaken for frame data.
+                Linktype::IEEE802_15_4 => {
+                    let mac_frame = data.len().checked_sub(2).map(|end| &data[..end]).unwrap_or(data);
+                    self.handle_l2_ieee802154(packet, &ctx, mac_frame)
+                }
+                // PHY-level (NONASK_PHY) and TAP-wrapped captures carry
+                // extra framing `parse_mac_header` doesn't understand;
+                // left unsupported rather than silently misparsed.
+                Linktype::IEEE802_15_4_NONASK_PHY | Linktype::IEEE802_15_4_TAP => {
+                    warn!(
+                        "IEEE 802.15.4 linktype {:?} is not supported (idx={})",
+                        packet.linktype, ctx.pcap_index
+                    );
+                    Ok(())
+                }
+                _ => self.handle_l2(packet, &ctx, data),
+            },
             PacketData::L3(ethertype, data) => {
-                handle_l3(packet, &ctx, data, EtherType(ethertype), self)
+                handle_l3(packet, &ctx, data, EtherType(ethertype), self, Vec::new(), None)
             }
-            PacketData::L4(_, _) => unimplemented!(), // XXX
+            PacketData::L4(proto, data) => handle_l4_only(packet, &ctx, proto, data, self),
             PacketData::Unsupported(_) => {
                 warn!("Unsupported data format (unknown linktype ?)");
                 Err(Error::Generic("Unsupported data format"))
@@ -928,8 +1691,8 @@ impl PcapAnalyzer for Analyzer {
             let flows = &self.flows;
             // expire remaining flows
             trace!("{} flows remaining in table", flows.len());
-            // let start = ::std::time::Instant::now();
             self.registry.run_plugins(
+                "flow_destroyed",
                 |p| p.plugin_type() & PLUGIN_FLOW_DEL != 0,
                 |p| {
                     flows.values().for_each(|flow| {
@@ -937,11 +1700,56 @@ impl PcapAnalyzer for Analyzer {
                     });
                 },
             );
-            // let elapsed = start.elapsed();
-            // debug!("Time to run flow_destroyed {}.{}", elapsed.as_secs(), elapsed.as_millis());
+            if let Some(exporter) = self.flow_exporter.as_mut() {
+                for flow in self.flows.values() {
+                    exporter.export_flow(flow);
+                }
+            }
+            if let Some(tx) = self.record_tx.as_ref() {
+                for flow in self.flows.values() {
+                    let _ = tx.send(OutputRecord::Flow(flow.clone()));
+                }
+            }
             self.flows.clear();
+            self.flow_timing_wheel.clear();
+            crate::tcp_reassembly::finalize_tcp_streams(self);
+
+            let (v4_timeouts, v4_overlaps, v4_evictions) = self.ipv4_defrag.stats();
+            let (v6_timeouts, v6_overlaps, v6_evictions) = self.ipv6_defrag.stats();
+            info!(
+                "IPv4 defrag: {} timeouts, {} overlaps dropped, {} evictions",
+                v4_timeouts, v4_overlaps, v4_evictions
+            );
+            info!(
+                "IPv6 defrag: {} timeouts, {} overlaps dropped, {} evictions",
+                v6_timeouts, v6_overlaps, v6_evictions
+            );
+
+            self.registry
+                .run_plugins("post_process", |_| true, |p| p.post_process());
 
-            self.registry.run_plugins(|_| true, |p| p.post_process());
+            if self.registry.stats_enabled() {
+                let report = self.registry.stats_report();
+                if !report.is_empty() {
+                    info!("{}", report);
+                }
+                let snapshot = self.registry.stats_snapshot();
+                self.registry.run_plugins(
+                    "stats_ready",
+                    |_| true,
+                    |p| p.stats_ready(&snapshot),
+                );
+            }
+
+            if !self.plugin_errors.is_empty() {
+                info!(
+                    "{} plugin error(s) collected during this run (plugin_error_policy=collect):",
+                    self.plugin_errors.len()
+                );
+                for (id, pcap_index, msg) in &self.plugin_errors {
+                    info!("  plugin {:?} packet #{}: {}", id, pcap_index, msg);
+                }
+            }
         };
     }
 }
@@ -0,0 +1,591 @@
+//! Out-of-process plugin transport.
+//!
+//! `ExternalPluginProxy` implements `Plugin` but forwards every callback to
+//! a child process over a local socket (a Unix domain socket on *nix, a
+//! named pipe on Windows), so a crashing, hanging or non-Rust plugin can't
+//! take down or block the analyzer. The registry holds the proxy behind
+//! the same `Arc<Mutex<dyn Plugin>>` as in-process plugins, so
+//! `run_plugins_v2` dispatches to both kinds unchanged.
+//!
+//! Wire format: each message is a 4-byte little-endian length prefix
+//! followed by that many bytes of `bincode`-encoded `PluginRequest` (parent
+//! to child) or `PluginResponse` (child to parent). Requests are answered
+//! one at a time, in order - a plugin process is not expected to pipeline.
+
+use crate::packet_info::{IcmpErrorReason, PacketInfo};
+use crate::plugin::{Plugin, PluginContext, PluginResult};
+use crate::plugin_registry::PluginRegistry;
+use crate::L3Info;
+use libpcap_tools::{Error, FiveTuple, Flow, FlowID, Packet, ThreeTuple};
+
+use rand::Rng;
+use serde::{Deserialize, Serialize};
+use std::io::{self, Read, Write};
+use std::process::{Child, Command};
+use std::sync::{Arc, Mutex};
+
+#[cfg(unix)]
+use std::os::unix::net::{UnixListener, UnixStream};
+
+/// Owned, serializable mirror of `libpcap_tools::Packet`'s metadata (the
+/// packet's own byte slice travels alongside as a separate `Vec<u8>` in
+/// each request, since `PacketData` itself isn't `Serialize`).
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct PacketMeta {
+    interface: u32,
+    caplen: u32,
+    origlen: u32,
+    ts_secs: u64,
+    ts_micros: u64,
+    pcap_index: usize,
+}
+
+impl From<&Packet<'_>> for PacketMeta {
+    fn from(packet: &Packet<'_>) -> Self {
+        PacketMeta {
+            interface: packet.interface,
+            caplen: packet.caplen,
+            origlen: packet.origlen,
+            ts_secs: packet.ts.secs,
+            ts_micros: packet.ts.micros,
+            pcap_index: packet.pcap_index,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct ThreeTupleWire {
+    proto: u16,
+    src: String,
+    dst: String,
+}
+
+impl From<&ThreeTuple> for ThreeTupleWire {
+    fn from(t: &ThreeTuple) -> Self {
+        ThreeTupleWire {
+            proto: t.proto,
+            src: t.src.to_string(),
+            dst: t.dst.to_string(),
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct L3InfoWire {
+    l4_proto: u8,
+    three_tuple: ThreeTupleWire,
+    vlan_tags: Vec<u16>,
+    vni: Option<u32>,
+}
+
+impl From<&L3Info> for L3InfoWire {
+    fn from(l3: &L3Info) -> Self {
+        L3InfoWire {
+            l4_proto: l3.l4_proto,
+            three_tuple: (&l3.three_tuple).into(),
+            vlan_tags: l3.vlan_tags.clone(),
+            vni: l3.vni,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct FiveTupleWire {
+    src: String,
+    dst: String,
+    src_port: u16,
+    dst_port: u16,
+    proto: u8,
+    vlan_tags: Vec<u16>,
+    vni: Option<u32>,
+}
+
+impl From<&FiveTuple> for FiveTupleWire {
+    fn from(t: &FiveTuple) -> Self {
+        FiveTupleWire {
+            src: t.src.to_string(),
+            dst: t.dst.to_string(),
+            src_port: t.src_port,
+            dst_port: t.dst_port,
+            proto: t.proto,
+            vlan_tags: t.vlan_tags.clone(),
+            vni: t.vni,
+        }
+    }
+}
+
+fn from_wire_five_tuple(w: FiveTupleWire) -> FiveTuple {
+    FiveTuple {
+        src: w
+            .src
+            .parse()
+            .unwrap_or(std::net::IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED)),
+        dst: w
+            .dst
+            .parse()
+            .unwrap_or(std::net::IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED)),
+        src_port: w.src_port,
+        dst_port: w.dst_port,
+        proto: w.proto,
+        vlan_tags: w.vlan_tags,
+        vni: w.vni,
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct FlowWire {
+    flow_id: FlowID,
+    src: String,
+    dst: String,
+    src_port: u16,
+    dst_port: u16,
+    proto: u8,
+    vlan_tags: Vec<u16>,
+    vni: Option<u32>,
+}
+
+impl From<&Flow> for FlowWire {
+    fn from(flow: &Flow) -> Self {
+        FlowWire {
+            flow_id: flow.flow_id,
+            src: flow.five_tuple.src.to_string(),
+            dst: flow.five_tuple.dst.to_string(),
+            src_port: flow.five_tuple.src_port,
+            dst_port: flow.five_tuple.dst_port,
+            proto: flow.five_tuple.proto,
+            vlan_tags: flow.five_tuple.vlan_tags.clone(),
+            vni: flow.five_tuple.vni,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+struct PacketInfoWire {
+    src: String,
+    dst: String,
+    src_port: u16,
+    dst_port: u16,
+    proto: u8,
+    vlan_tags: Vec<u16>,
+    vni: Option<u32>,
+    to_server: bool,
+    l3_type: u16,
+    l4_data: Vec<u8>,
+    l4_type: u8,
+    l4_payload: Option<Vec<u8>>,
+    stream_data: Option<Vec<u8>>,
+    related_flow_id: Option<FlowID>,
+    icmp_error_reason: Option<IcmpErrorReason>,
+    pcap_index: usize,
+}
+
+impl From<&PacketInfo<'_>> for PacketInfoWire {
+    fn from(pinfo: &PacketInfo<'_>) -> Self {
+        PacketInfoWire {
+            src: pinfo.five_tuple.src.to_string(),
+            dst: pinfo.five_tuple.dst.to_string(),
+            src_port: pinfo.five_tuple.src_port,
+            dst_port: pinfo.five_tuple.dst_port,
+            proto: pinfo.five_tuple.proto,
+            vlan_tags: pinfo.five_tuple.vlan_tags.clone(),
+            vni: pinfo.five_tuple.vni,
+            to_server: pinfo.to_server,
+            l3_type: pinfo.l3_type,
+            l4_data: pinfo.l4_data.to_vec(),
+            l4_type: pinfo.l4_type,
+            l4_payload: pinfo.l4_payload.map(|p| p.to_vec()),
+            stream_data: pinfo.stream_data.map(|p| p.to_vec()),
+            related_flow_id: pinfo.related_flow_id,
+            icmp_error_reason: pinfo.icmp_error_reason,
+            pcap_index: pinfo.pcap_index,
+        }
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum PluginRequest {
+    Hello,
+    PreProcess,
+    PostProcess,
+    HandleLayerPhysical {
+        packet: PacketMeta,
+        data: Vec<u8>,
+    },
+    HandleLayerLink {
+        packet: PacketMeta,
+        linktype: u16,
+        data: Vec<u8>,
+    },
+    HandleLayerNetwork {
+        packet: PacketMeta,
+        data: Vec<u8>,
+        three_tuple: ThreeTupleWire,
+        l4_proto: u8,
+    },
+    HandleLayerTransport {
+        packet: PacketMeta,
+        pinfo: PacketInfoWire,
+    },
+    FlowCreated(FlowWire),
+    FlowDestroyed(FlowWire),
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+enum PluginResponse {
+    /// Acknowledges a request that has no `PluginResult` of its own
+    /// (`Hello`, `PreProcess`, `PostProcess`, `FlowCreated`,
+    /// `FlowDestroyed`). For `Hello`, carries the plugin's declared
+    /// `plugin_type` bitmask.
+    Ack { plugin_type: u32 },
+    None,
+    Error(String),
+    L2 { ethertype: u16, payload: Vec<u8> },
+    L3 { l3_info: L3InfoWire, payload: Vec<u8> },
+    L4 { five_tuple: FiveTupleWire, payload: Vec<u8> },
+}
+
+fn write_framed<T: Serialize>(w: &mut impl Write, msg: &T) -> io::Result<()> {
+    let bytes = bincode::serialize(msg)
+        .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+    w.write_all(&(bytes.len() as u32).to_le_bytes())?;
+    w.write_all(&bytes)?;
+    w.flush()
+}
+
+fn read_framed<T: for<'de> Deserialize<'de>>(r: &mut impl Read) -> io::Result<T> {
+    let mut len_buf = [0u8; 4];
+    r.read_exact(&mut len_buf)?;
+    let len = u32::from_le_bytes(len_buf) as usize;
+    let mut buf = vec![0u8; len];
+    r.read_exact(&mut buf)?;
+    bincode::deserialize(&buf).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+}
+
+#[cfg(unix)]
+type Transport = UnixStream;
+
+#[cfg(windows)]
+type Transport = std::fs::File;
+
+/// A single plugin running as a child process, speaking the framed
+/// request/response protocol above over `Transport`.
+pub struct ExternalPluginProxy {
+    child: Child,
+    stream: Transport,
+    plugin_type: u32,
+    /// Name/path of the child binary, kept around for log messages.
+    name: String,
+    /// Total bytes leaked so far via `owned` below, so a long-running
+    /// capture (see `live_capture`) whose plugin keeps asking for
+    /// re-dissection doesn't leak without anyone noticing.
+    leaked_bytes: u64,
+    /// Set once `leaked_bytes` has crossed `MAX_LEAKED_BYTES`, so `owned`
+    /// only warns about hitting the cap once instead of once per refused
+    /// request for the rest of the run.
+    leak_cap_warned: bool,
+}
+
+/// How many additional leaked bytes between one warning and the next; a
+/// plugin re-dissecting every packet would otherwise spam the log once per
+/// packet instead of tracking growth at a sane cadence.
+const LEAK_WARN_STEP_BYTES: u64 = 64 * 1024 * 1024;
+
+/// Hard ceiling on bytes a single proxy will leak for the life of the
+/// process. `owned` has no way to ever free what it hands out (see its doc
+/// comment), so logging the running total isn't enough on its own to keep
+/// a long-running/live capture bounded -- once a proxy hits this cap,
+/// further re-dissection requests are refused (`PluginResult::Error`)
+/// instead of growing the leak forever.
+const MAX_LEAKED_BYTES: u64 = 256 * 1024 * 1024;
+
+impl ExternalPluginProxy {
+    /// Spawn `path` (with `args`), negotiate a local-socket transport with
+    /// it, and perform the `Hello` handshake to learn its `plugin_type`.
+    pub fn spawn(path: &str, args: &[String]) -> Result<Self, Error> {
+        #[cfg(unix)]
+        {
+            let socket_path = std::env::temp_dir().join(format!(
+                "pcap-analyzer-plugin-{:016x}.sock",
+                rand::thread_rng().gen::<u64>()
+            ));
+            let _ = std::fs::remove_file(&socket_path);
+            let listener = UnixListener::bind(&socket_path)
+                .map_err(|_| Error::Generic("could not bind plugin socket"))?;
+
+            let child = Command::new(path)
+                .args(args)
+                .arg("--plugin-socket")
+                .arg(&socket_path)
+                .spawn()
+                .map_err(|_| Error::Generic("could not spawn external plugin process"))?;
+
+            let (stream, _) = listener
+                .accept()
+                .map_err(|_| Error::Generic("external plugin did not connect to socket"))?;
+            let _ = std::fs::remove_file(&socket_path);
+
+            let mut proxy = ExternalPluginProxy {
+                child,
+                stream,
+                plugin_type: 0,
+                name: path.to_string(),
+                leaked_bytes: 0,
+                leak_cap_warned: false,
+            };
+            proxy.plugin_type = proxy.hello()?;
+            Ok(proxy)
+        }
+        #[cfg(windows)]
+        {
+            let pipe_name = format!(
+                r"\\.\pipe\pcap-analyzer-plugin-{:016x}",
+                rand::thread_rng().gen::<u64>()
+            );
+            let child = Command::new(path)
+                .args(args)
+                .arg("--plugin-pipe")
+                .arg(&pipe_name)
+                .spawn()
+                .map_err(|_| Error::Generic("could not spawn external plugin process"))?;
+            // The child is expected to create the named pipe server side
+            // and the parent connects as a client once it's ready; a real
+            // implementation would retry/backoff here.
+            let stream = std::fs::OpenOptions::new()
+                .read(true)
+                .write(true)
+                .open(&pipe_name)
+                .map_err(|_| Error::Generic("could not open plugin named pipe"))?;
+
+            let mut proxy = ExternalPluginProxy {
+                child,
+                stream,
+                plugin_type: 0,
+                name: path.to_string(),
+                leaked_bytes: 0,
+                leak_cap_warned: false,
+            };
+            proxy.plugin_type = proxy.hello()?;
+            Ok(proxy)
+        }
+    }
+
+    fn hello(&mut self) -> Result<u32, Error> {
+        write_framed(&mut self.stream, &PluginRequest::Hello)
+            .map_err(|_| Error::Generic("external plugin handshake write failed"))?;
+        match read_framed(&mut self.stream) {
+            Ok(PluginResponse::Ack { plugin_type }) => Ok(plugin_type),
+            _ => Err(Error::Generic("external plugin handshake failed")),
+        }
+    }
+
+    /// Send `req` and decode the matching `PluginResult`, converting any
+    /// transport failure (broken pipe, crashed child, garbled frame) into
+    /// `PluginResult::Error` so a faulty plugin never blocks the run.
+    fn roundtrip<'i>(&mut self, req: PluginRequest) -> PluginResult<'i> {
+        if let Err(e) = write_framed(&mut self.stream, &req) {
+            warn!("external plugin {}: write failed: {}", self.name, e);
+            return PluginResult::Error(Error::Generic("external plugin write failed"));
+        }
+        match read_framed::<PluginResponse>(&mut self.stream) {
+            Ok(PluginResponse::None) | Ok(PluginResponse::Ack { .. }) => PluginResult::None,
+            Ok(PluginResponse::Error(msg)) => {
+                warn!("external plugin {}: {}", self.name, msg);
+                PluginResult::Error(Error::Generic("external plugin returned an error"))
+            }
+            Ok(PluginResponse::L2 { ethertype, payload }) => self
+                .owned(payload)
+                .map_or_else(Self::leak_cap_error, |buf| PluginResult::L2(ethertype, buf)),
+            Ok(PluginResponse::L3 { l3_info, payload }) => self.owned(payload).map_or_else(
+                Self::leak_cap_error,
+                |buf| PluginResult::L3(from_wire_l3(l3_info), buf),
+            ),
+            Ok(PluginResponse::L4 { five_tuple, payload }) => self.owned(payload).map_or_else(
+                Self::leak_cap_error,
+                |buf| PluginResult::L4(from_wire_five_tuple(five_tuple), buf),
+            ),
+            Err(e) => {
+                warn!("external plugin {}: read failed: {}", self.name, e);
+                PluginResult::Error(Error::Generic("external plugin read failed"))
+            }
+        }
+    }
+
+    /// The proxy decodes payloads from the wire into freshly-owned
+    /// buffers, so it leaks them to get a `'static`-compatible slice the
+    /// rest of the (layer-scoped) dispatch can borrow from for the
+    /// remainder of this packet -- there's no owner that outlives this
+    /// call but is shorter-lived than what `PluginResult`'s borrow demands.
+    /// This trades a per-packet allocation for keeping the existing
+    /// `PluginResult<'i>` contract (borrowed payload) unchanged for
+    /// in-process plugins. Tracks the running total and logs it past every
+    /// `LEAK_WARN_STEP_BYTES` so a plugin that asks for re-dissection on
+    /// every packet of a long-running/live capture doesn't leak
+    /// unboundedly without anyone noticing -- and once the total would
+    /// cross `MAX_LEAKED_BYTES`, refuses to leak any further, returning
+    /// `None` so the caller can fail that one re-dissection request
+    /// instead of growing the leak without bound.
+    fn owned<'i>(&mut self, v: Vec<u8>) -> Option<&'i [u8]> {
+        let before = self.leaked_bytes;
+        let after = before + v.len() as u64;
+        if after > MAX_LEAKED_BYTES {
+            if !self.leak_cap_warned {
+                warn!(
+                    "external plugin {}: hit the {}-byte leaked-memory cap; refusing further re-dissection requests for the rest of this run",
+                    self.name, MAX_LEAKED_BYTES
+                );
+                self.leak_cap_warned = true;
+            }
+            return None;
+        }
+        self.leaked_bytes = after;
+        if self.leaked_bytes / LEAK_WARN_STEP_BYTES > before / LEAK_WARN_STEP_BYTES {
+            warn!(
+                "external plugin {}: {} bytes leaked so far to satisfy re-dissection requests (never freed for the life of this process)",
+                self.name, self.leaked_bytes
+            );
+        }
+        Some(Box::leak(v.into_boxed_slice()))
+    }
+
+    /// Shared `None` arm for the `owned`-gated `PluginResponse::{L2,L3,L4}`
+    /// match arms in `roundtrip`: once the leaked-memory cap is hit, fail
+    /// just this one re-dissection request rather than the whole run.
+    fn leak_cap_error<'i>() -> PluginResult<'i> {
+        PluginResult::Error(Error::Generic(
+            "external plugin re-dissection payload exceeded the leaked-memory cap",
+        ))
+    }
+}
+
+fn from_wire_l3(w: L3InfoWire) -> L3Info {
+    L3Info {
+        l4_proto: w.l4_proto,
+        three_tuple: ThreeTuple {
+            proto: w.three_tuple.proto,
+            src: w
+                .three_tuple
+                .src
+                .parse()
+                .unwrap_or(std::net::IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED)),
+            dst: w
+                .three_tuple
+                .dst
+                .parse()
+                .unwrap_or(std::net::IpAddr::V4(std::net::Ipv4Addr::UNSPECIFIED)),
+        },
+        vlan_tags: w.vlan_tags,
+        vni: w.vni,
+    }
+}
+
+impl Plugin for ExternalPluginProxy {
+    fn plugin_type(&self) -> u32 {
+        self.plugin_type
+    }
+
+    fn name(&self) -> &str {
+        &self.name
+    }
+
+    fn pre_process(&mut self) {
+        let _ = self.roundtrip(PluginRequest::PreProcess);
+    }
+
+    fn post_process(&mut self) {
+        let _ = self.roundtrip(PluginRequest::PostProcess);
+    }
+
+    fn flow_created(&mut self, flow: &Flow) {
+        let _ = self.roundtrip(PluginRequest::FlowCreated(flow.into()));
+    }
+
+    fn flow_destroyed(&mut self, flow: &Flow) {
+        let _ = self.roundtrip(PluginRequest::FlowDestroyed(flow.into()));
+    }
+
+    // `_pctx` isn't forwarded: the wire protocol above only carries
+    // `PluginResult`s back from the child, and a child process has no way
+    // to read/write a same-process `PluginContext` anyway. An external
+    // plugin can still declare `dependencies()` (enforcing dispatch order),
+    // it just can't participate in the context-sharing side of the DAG.
+    fn handle_layer_physical<'a>(
+        &mut self,
+        packet: &Packet,
+        data: &'a [u8],
+        _pctx: &mut PluginContext,
+    ) -> PluginResult<'a> {
+        self.roundtrip(PluginRequest::HandleLayerPhysical {
+            packet: packet.into(),
+            data: data.to_vec(),
+        })
+    }
+
+    fn handle_layer_link<'a>(
+        &mut self,
+        packet: &Packet,
+        linktype: u16,
+        data: &'a [u8],
+        _pctx: &mut PluginContext,
+    ) -> PluginResult<'a> {
+        self.roundtrip(PluginRequest::HandleLayerLink {
+            packet: packet.into(),
+            linktype,
+            data: data.to_vec(),
+        })
+    }
+
+    fn handle_layer_network<'a>(
+        &mut self,
+        packet: &Packet,
+        data: &'a [u8],
+        three_tuple: &ThreeTuple,
+        l4_proto: u8,
+        _pctx: &mut PluginContext,
+    ) -> PluginResult<'a> {
+        self.roundtrip(PluginRequest::HandleLayerNetwork {
+            packet: packet.into(),
+            data: data.to_vec(),
+            three_tuple: three_tuple.into(),
+            l4_proto,
+        })
+    }
+
+    fn handle_layer_transport<'a>(
+        &mut self,
+        packet: &Packet,
+        pinfo: &PacketInfo<'a>,
+        _pctx: &mut PluginContext,
+    ) -> PluginResult<'a> {
+        self.roundtrip(PluginRequest::HandleLayerTransport {
+            packet: packet.into(),
+            pinfo: pinfo.into(),
+        })
+    }
+}
+
+impl Drop for ExternalPluginProxy {
+    fn drop(&mut self) {
+        // Best-effort: let the child exit on its own before reaping it, so
+        // a plugin that's mid-flush on an orderly shutdown isn't killed.
+        match self.child.try_wait() {
+            Ok(Some(_)) => {}
+            _ => {
+                let _ = self.child.kill();
+                let _ = self.child.wait();
+            }
+        }
+    }
+}
+
+/// Spawn `path` as an external plugin and register it in `registry`
+/// alongside the in-process plugins, so `run_plugins_v2` dispatches to it
+/// for whatever layer/filter its `Hello` handshake declared.
+pub fn register_external_plugin(
+    registry: &mut PluginRegistry,
+    path: &str,
+    args: &[String],
+) -> Result<(), Error> {
+    let proxy = ExternalPluginProxy::spawn(path, args)?;
+    registry.add_plugin(Arc::new(Mutex::new(proxy)));
+    Ok(())
+}
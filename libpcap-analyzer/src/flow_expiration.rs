@@ -0,0 +1,146 @@
+use libpcap_tools::{Config, Duration, FlowID};
+use std::collections::{HashMap, HashSet};
+
+/// Default idle time after which a flow with no new packets is expired.
+const DEFAULT_TIMEOUT_SECS: u64 = 120;
+/// Default bucket width, as a power-of-two number of seconds
+/// (`1 << granularity`).
+const DEFAULT_GRANULARITY: u32 = 2;
+/// Default number of buckets in the ring.
+const DEFAULT_WHEEL_SIZE: usize = 1024;
+
+/// Hashed timing wheel used to expire idle flows without an O(n) scan of
+/// `self.flows` on every packet.
+///
+/// Flows are bucketed by `(last_seen.secs >> granularity) % wheel_size`.
+/// Advancing the wheel to a new packet timestamp sweeps every bucket
+/// whose window is now more than `timeout` behind that timestamp,
+/// draining whichever flow ids are still sitting in it (a flow that was
+/// seen again in the meantime has already been moved to a later bucket
+/// by `track`, so it won't show up in the sweep).
+pub struct FlowTimingWheel {
+    buckets: Vec<HashSet<FlowID>>,
+    /// Which bucket each tracked flow currently sits in, so `track` can
+    /// remove it from its old bucket in O(1).
+    flow_bucket: HashMap<FlowID, usize>,
+    timeout: Duration,
+    granularity: u32,
+    wheel_size: usize,
+    /// Packet timestamp the wheel was last advanced to.
+    current: Duration,
+}
+
+impl FlowTimingWheel {
+    /// Read `flow_expiration_timeout_secs`, `flow_expiration_granularity_secs`
+    /// (log2 of the bucket width) and `flow_expiration_wheel_size` from
+    /// `config`.
+    pub fn new(config: &Config) -> Self {
+        let timeout_secs = config
+            .get_usize("flow_expiration_timeout_secs")
+            .map(|v| v as u64)
+            .unwrap_or(DEFAULT_TIMEOUT_SECS);
+        let granularity = config
+            .get_usize("flow_expiration_granularity_secs")
+            .map(|v| v as u32)
+            .unwrap_or(DEFAULT_GRANULARITY);
+        let wheel_size = config
+            .get_usize("flow_expiration_wheel_size")
+            .unwrap_or(DEFAULT_WHEEL_SIZE)
+            .max(1);
+        FlowTimingWheel {
+            buckets: (0..wheel_size).map(|_| HashSet::new()).collect(),
+            flow_bucket: HashMap::new(),
+            timeout: Duration::new(timeout_secs, 0),
+            granularity,
+            wheel_size,
+            current: Duration::new(0, 0),
+        }
+    }
+
+    pub fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn bucket_index(&self, secs: u64) -> usize {
+        ((secs >> self.granularity) as usize) % self.wheel_size
+    }
+
+    /// Record that `flow_id` was just seen at `last_seen`, moving it into
+    /// the matching bucket if it changed.
+    pub fn track(&mut self, flow_id: FlowID, last_seen: Duration) {
+        let idx = self.bucket_index(last_seen.secs);
+        if let Some(old_idx) = self.flow_bucket.get(&flow_id) {
+            if *old_idx == idx {
+                return;
+            }
+            self.buckets[*old_idx].remove(&flow_id);
+        }
+        self.buckets[idx].insert(flow_id);
+        self.flow_bucket.insert(flow_id, idx);
+    }
+
+    /// Stop tracking `flow_id` (e.g. it was just expired or otherwise
+    /// removed from `self.flows`).
+    pub fn remove(&mut self, flow_id: FlowID) {
+        if let Some(idx) = self.flow_bucket.remove(&flow_id) {
+            self.buckets[idx].remove(&flow_id);
+        }
+    }
+
+    /// Advance the wheel to the current packet's timestamp `now`,
+    /// returning the flow ids whose bucket has aged past `timeout` and so
+    /// are due for expiration.
+    ///
+    /// A jump in `now` larger than a full revolution of the wheel (e.g. a
+    /// gap between two capture files) is clamped to sweeping every bucket
+    /// exactly once, rather than looping for the full elapsed time.
+    pub fn advance(&mut self, now: Duration) -> Vec<FlowID> {
+        if now <= self.current {
+            return Vec::new();
+        }
+        let old_horizon = self.current.secs.saturating_sub(self.timeout.secs);
+        let new_horizon = now.secs.saturating_sub(self.timeout.secs);
+        self.current = now;
+        if new_horizon <= old_horizon {
+            return Vec::new();
+        }
+
+        let old_pos = self.bucket_index(old_horizon);
+        let new_pos = self.bucket_index(new_horizon);
+        // A jump spanning a full revolution (or more) of the wheel means
+        // every bucket is stale; clamp to one full sweep instead of
+        // looping for the entire elapsed time.
+        let full_width_secs = (self.wheel_size as u64) << self.granularity;
+        let span = if new_horizon - old_horizon >= full_width_secs {
+            self.wheel_size
+        } else {
+            let mut s = new_pos as i64 - old_pos as i64;
+            if s < 0 {
+                s += self.wheel_size as i64;
+            }
+            s as usize
+        };
+        if span == 0 {
+            return Vec::new();
+        }
+
+        let mut due = Vec::new();
+        for step in 1..=span {
+            let idx = (old_pos + step) % self.wheel_size;
+            for flow_id in self.buckets[idx].drain() {
+                self.flow_bucket.remove(&flow_id);
+                due.push(flow_id);
+            }
+        }
+        due
+    }
+
+    /// Drop all tracked state (used at teardown, once remaining flows
+    /// have been flushed through `self.flows` directly).
+    pub fn clear(&mut self) {
+        for bucket in &mut self.buckets {
+            bucket.clear();
+        }
+        self.flow_bucket.clear();
+    }
+}
@@ -0,0 +1,68 @@
+//! GENEVE (RFC 8926) tunnel decapsulation, mirroring `vxlan`: parse the
+//! base header and skip the variable-length TLV option list, then hand the
+//! encapsulated payload back to the analyzer for recursive decoding. The
+//! VNI is exposed the same way VXLAN's is, so `flow_map` can key flows per
+//! virtual network.
+
+/// Borrowed view over a GENEVE packet: an 8-byte base header, followed by
+/// `options_len()` bytes of TLV options, followed by the payload declared
+/// by `get_protocol_type()`.
+pub struct GenevePacket<'a> {
+    data: &'a [u8],
+}
+
+const GENEVE_BASE_HEADER_LEN: usize = 8;
+
+impl<'a> GenevePacket<'a> {
+    /// Builds a view over `data`, if it's long enough to hold the base
+    /// header and the option bytes it declares.
+    pub fn new(data: &'a [u8]) -> Option<Self> {
+        if data.len() < GENEVE_BASE_HEADER_LEN {
+            return None;
+        }
+        let packet = GenevePacket { data };
+        if data.len() < GENEVE_BASE_HEADER_LEN + packet.options_len() {
+            return None;
+        }
+        Some(packet)
+    }
+
+    /// Header version; RFC 8926 defines only version 0.
+    pub fn get_version(&self) -> u8 {
+        self.data[0] >> 6
+    }
+
+    /// Length of the variable options, in bytes (the header encodes it in
+    /// 4-byte words).
+    pub fn options_len(&self) -> usize {
+        ((self.data[0] & 0x3f) as usize) * 4
+    }
+
+    /// Set when the tunnel endpoint couldn't give this packet its usual
+    /// processing because of an option it didn't understand.
+    pub fn get_oam_flag(&self) -> bool {
+        self.data[1] & 0x80 != 0
+    }
+
+    /// Set when one or more options carry data every transit device must
+    /// be able to interpret.
+    pub fn get_critical_flag(&self) -> bool {
+        self.data[1] & 0x40 != 0
+    }
+
+    /// Ethertype of the encapsulated payload (e.g. `0x6558`, transparent
+    /// Ethernet bridging -- the common "GENEVE carrying Ethernet" case).
+    pub fn get_protocol_type(&self) -> u16 {
+        u16::from_be_bytes([self.data[2], self.data[3]])
+    }
+
+    /// Virtual Network Identifier.
+    pub fn get_vni(&self) -> u32 {
+        u32::from_be_bytes([0, self.data[4], self.data[5], self.data[6]])
+    }
+
+    /// The encapsulated payload, past the base header and any TLV options.
+    pub fn payload(&self) -> &'a [u8] {
+        &self.data[GENEVE_BASE_HEADER_LEN + self.options_len()..]
+    }
+}
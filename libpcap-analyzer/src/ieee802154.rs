@@ -0,0 +1,533 @@
+//! IEEE 802.15.4 MAC header parsing and 6LoWPAN (RFC 6282) IPHC
+//! decompression, used to recover a full IPv6 datagram from frames
+//! captured on a low-power mesh (e.g. Zigbee, Thread) link.
+
+use std::collections::HashMap;
+use std::net::Ipv6Addr;
+
+/// Addressing mode used for source/destination in the 802.15.4 MAC
+/// header (from the frame control field).
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum Ieee802154Addr {
+    None,
+    Short(u16),
+    Extended(u64),
+}
+
+/// Parsed IEEE 802.15.4 MAC header (data frames only).
+#[derive(Clone, Debug)]
+pub struct Ieee802154Header {
+    pub frame_type: u8,
+    pub sequence_number: u8,
+    pub dst_pan_id: Option<u16>,
+    pub dst_addr: Ieee802154Addr,
+    pub src_pan_id: Option<u16>,
+    pub src_addr: Ieee802154Addr,
+}
+
+const FRAME_TYPE_DATA: u8 = 0b001;
+
+/// Parse the 802.15.4 MAC header out of `data`, returning the header and
+/// the remaining payload (MAC footer/FCS is assumed already stripped by
+/// the capture, as is common for software-defined radio captures).
+pub fn parse_mac_header(data: &[u8]) -> Option<(Ieee802154Header, &[u8])> {
+    if data.len() < 3 {
+        return None;
+    }
+    let fcf = u16::from_le_bytes([data[0], data[1]]);
+    let frame_type = (fcf & 0x7) as u8;
+    let pan_id_compression = (fcf >> 6) & 0x1 != 0;
+    let dst_addr_mode = (fcf >> 10) & 0x3;
+    let src_addr_mode = (fcf >> 14) & 0x3;
+    let sequence_number = data[2];
+
+    let mut off = 3;
+    let mut dst_pan_id = None;
+    let mut dst_addr = Ieee802154Addr::None;
+    if dst_addr_mode != 0 {
+        if data.len() < off + 2 {
+            return None;
+        }
+        dst_pan_id = Some(u16::from_le_bytes([data[off], data[off + 1]]));
+        off += 2;
+        dst_addr = match dst_addr_mode {
+            0b10 => {
+                if data.len() < off + 2 {
+                    return None;
+                }
+                let a = u16::from_le_bytes([data[off], data[off + 1]]);
+                off += 2;
+                Ieee802154Addr::Short(a)
+            }
+            0b11 => {
+                if data.len() < off + 8 {
+                    return None;
+                }
+                let mut b = [0u8; 8];
+                b.copy_from_slice(&data[off..off + 8]);
+                off += 8;
+                Ieee802154Addr::Extended(u64::from_le_bytes(b))
+            }
+            _ => Ieee802154Addr::None,
+        };
+    }
+
+    let mut src_pan_id = None;
+    let mut src_addr = Ieee802154Addr::None;
+    if src_addr_mode != 0 {
+        if !pan_id_compression {
+            if data.len() < off + 2 {
+                return None;
+            }
+            src_pan_id = Some(u16::from_le_bytes([data[off], data[off + 1]]));
+            off += 2;
+        } else {
+            src_pan_id = dst_pan_id;
+        }
+        src_addr = match src_addr_mode {
+            0b10 => {
+                if data.len() < off + 2 {
+                    return None;
+                }
+                let a = u16::from_le_bytes([data[off], data[off + 1]]);
+                off += 2;
+                Ieee802154Addr::Short(a)
+            }
+            0b11 => {
+                if data.len() < off + 8 {
+                    return None;
+                }
+                let mut b = [0u8; 8];
+                b.copy_from_slice(&data[off..off + 8]);
+                off += 8;
+                Ieee802154Addr::Extended(u64::from_le_bytes(b))
+            }
+            _ => Ieee802154Addr::None,
+        };
+    }
+
+    let header = Ieee802154Header {
+        frame_type,
+        sequence_number,
+        dst_pan_id,
+        dst_addr,
+        src_pan_id,
+        src_addr,
+    };
+    Some((header, &data[off..]))
+}
+
+pub fn is_data_frame(header: &Ieee802154Header) -> bool {
+    header.frame_type == FRAME_TYPE_DATA
+}
+
+/// Hop limit values encoded by the IPHC HLIM field.
+fn expand_hlim(hlim_bits: u8, data: &[u8], off: &mut usize) -> Option<u8> {
+    match hlim_bits {
+        0b00 => {
+            let v = *data.get(*off)?;
+            *off += 1;
+            Some(v)
+        }
+        0b01 => Some(1),
+        0b10 => Some(64),
+        0b11 => Some(255),
+        _ => None,
+    }
+}
+
+/// Build the 64-bit interface identifier implied by an elided 802.15.4
+/// address (EUI-64 for extended addresses, `ff:fe00:xxxx` for short
+/// addresses, as specified by RFC 4944 6.5).
+fn iid_from_mac(addr: Ieee802154Addr) -> [u8; 8] {
+    match addr {
+        Ieee802154Addr::Extended(a) => {
+            let mut b = a.to_be_bytes();
+            // invert the universal/local bit, as for normal EUI-64 -> IID
+            b[0] ^= 0x02;
+            b
+        }
+        Ieee802154Addr::Short(a) => {
+            let a = a.to_be_bytes();
+            [0, 0, 0, 0xff, 0xfe, 0, a[0], a[1]]
+        }
+        Ieee802154Addr::None => [0; 8],
+    }
+}
+
+fn addr_from_context_or_link_local(iid: [u8; 8]) -> Ipv6Addr {
+    let mut segs = [0u16; 8];
+    segs[0] = 0xfe80;
+    for i in 0..4 {
+        segs[4 + i] = u16::from_be_bytes([iid[2 * i], iid[2 * i + 1]]);
+    }
+    Ipv6Addr::new(
+        segs[0], segs[1], segs[2], segs[3], segs[4], segs[5], segs[6], segs[7],
+    )
+}
+
+/// Decompress a LOWPAN_IPHC-encoded datagram (RFC 6282) into a full
+/// 40-byte IPv6 header followed by payload, given the link-layer
+/// addresses the frame carried (used to rebuild elided addresses).
+///
+/// Returns `None` if the dispatch byte is not `011xxxxx` or the buffer is
+/// truncated.
+pub fn decompress_iphc(
+    data: &[u8],
+    src_mac: Ieee802154Addr,
+    dst_mac: Ieee802154Addr,
+) -> Option<Vec<u8>> {
+    if data.len() < 2 {
+        return None;
+    }
+    if data[0] >> 5 != 0b011 {
+        // not a LOWPAN_IPHC dispatch
+        return None;
+    }
+    let b0 = data[0];
+    let b1 = data[1];
+    let tf = (b0 >> 3) & 0x3;
+    let nh_compressed = (b0 >> 2) & 0x1 != 0;
+    let hlim_bits = b0 & 0x3;
+
+    let cid = (b1 >> 7) & 0x1 != 0;
+    let sac = (b1 >> 6) & 0x1 != 0;
+    let sam = (b1 >> 4) & 0x3;
+    let m = (b1 >> 3) & 0x1 != 0;
+    let dac = (b1 >> 2) & 0x1 != 0;
+    let dam = b1 & 0x3;
+
+    let mut off = 2;
+    if cid {
+        // context identifier extension: one byte, contexts not modeled
+        // here beyond skipping it (no stored context table)
+        off += 1;
+    }
+
+    // traffic class / flow label (TF field)
+    let (tc, fl) = match tf {
+        0b00 => {
+            if data.len() < off + 4 {
+                return None;
+            }
+            let ecn = data[off] >> 6;
+            let dscp = data[off] & 0x3f;
+            let fl = (u32::from(data[off + 1] & 0xf) << 16)
+                | (u32::from(data[off + 2]) << 8)
+                | u32::from(data[off + 3]);
+            off += 4;
+            ((ecn << 6) | dscp, fl)
+        }
+        0b01 => {
+            if data.len() < off + 3 {
+                return None;
+            }
+            let ecn = data[off] >> 6;
+            let fl = (u32::from(data[off] & 0xf) << 16)
+                | (u32::from(data[off + 1]) << 8)
+                | u32::from(data[off + 2]);
+            off += 3;
+            (ecn << 6, fl)
+        }
+        0b10 => {
+            if data.len() < off + 1 {
+                return None;
+            }
+            let tc = data[off];
+            off += 1;
+            (tc, 0)
+        }
+        _ => (0, 0),
+    };
+
+    let next_header = if nh_compressed {
+        // resolved once we decode LOWPAN_NHC below; UDP is the only NHC
+        // form handled here (RFC 6282 3.1)
+        17u8 // IPPROTO_UDP
+    } else {
+        let v = *data.get(off)?;
+        off += 1;
+        v
+    };
+
+    let hop_limit = expand_hlim(hlim_bits, data, &mut off)?;
+
+    let src_addr = if sac {
+        if sam == 0b00 {
+            // unspecified address (::) - stateless, no context
+            Ipv6Addr::UNSPECIFIED
+        } else {
+            addr_from_context_or_link_local(iid_from_mac(src_mac))
+        }
+    } else {
+        match sam {
+            0b00 => {
+                // RFC 6282 3.2.1: full 128-bit address carried inline, not
+                // a link-local IID -- use the 16 bytes as-is rather than
+                // routing them through `addr_from_context_or_link_local`,
+                // which would discard the real high 64 bits and stamp a
+                // bogus `fe80::` prefix over them.
+                if data.len() < off + 16 {
+                    return None;
+                }
+                let mut b = [0u8; 16];
+                b.copy_from_slice(&data[off..off + 16]);
+                off += 16;
+                Ipv6Addr::from(b)
+            }
+            0b01 => {
+                if data.len() < off + 8 {
+                    return None;
+                }
+                let mut b = [0u8; 8];
+                b.copy_from_slice(&data[off..off + 8]);
+                off += 8;
+                addr_from_context_or_link_local(b)
+            }
+            0b10 => {
+                if data.len() < off + 2 {
+                    return None;
+                }
+                let short = u16::from_be_bytes([data[off], data[off + 1]]);
+                off += 2;
+                addr_from_context_or_link_local(iid_from_mac(Ieee802154Addr::Short(short)))
+            }
+            0b11 => addr_from_context_or_link_local(iid_from_mac(src_mac)),
+            _ => addr_from_context_or_link_local([0; 8]),
+        }
+    };
+
+    let dst_addr = if m {
+        // multicast destination: not modeled, fall back to link-local
+        // all-nodes; real deployments rarely hit this on unicast flows
+        addr_from_context_or_link_local([0xff; 8])
+    } else if dac {
+        addr_from_context_or_link_local(iid_from_mac(dst_mac))
+    } else {
+        match dam {
+            0b00 => {
+                // see the matching `sam` arm above: a full inline address,
+                // not an IID to prefix with `fe80::`.
+                if data.len() < off + 16 {
+                    return None;
+                }
+                let mut b = [0u8; 16];
+                b.copy_from_slice(&data[off..off + 16]);
+                off += 16;
+                Ipv6Addr::from(b)
+            }
+            0b01 => {
+                if data.len() < off + 8 {
+                    return None;
+                }
+                let mut b = [0u8; 8];
+                b.copy_from_slice(&data[off..off + 8]);
+                off += 8;
+                addr_from_context_or_link_local(b)
+            }
+            0b10 => {
+                if data.len() < off + 2 {
+                    return None;
+                }
+                let short = u16::from_be_bytes([data[off], data[off + 1]]);
+                off += 2;
+                addr_from_context_or_link_local(iid_from_mac(Ieee802154Addr::Short(short)))
+            }
+            0b11 => addr_from_context_or_link_local(iid_from_mac(dst_mac)),
+            _ => addr_from_context_or_link_local([0; 8]),
+        }
+    };
+
+    let (next_header, payload): (u8, Vec<u8>) = if nh_compressed {
+        decode_nhc_udp(data, off)?
+    } else {
+        (next_header, data[off..].to_vec())
+    };
+
+    let mut out = Vec::with_capacity(40 + payload.len());
+    out.push(0x60 | (tc >> 4));
+    out.push((tc << 4) | ((fl >> 16) as u8 & 0xf));
+    out.push((fl >> 8) as u8);
+    out.push(fl as u8);
+    let plen = payload.len() as u16;
+    out.extend_from_slice(&plen.to_be_bytes());
+    out.push(next_header);
+    out.push(hop_limit);
+    out.extend_from_slice(&src_addr.octets());
+    out.extend_from_slice(&dst_addr.octets());
+    out.extend_from_slice(&payload);
+
+    Some(out)
+}
+
+/// Decode a LOWPAN_NHC UDP header (RFC 6282 4.3), returning
+/// `(IPPROTO_UDP, rebuilt UDP segment)`: the elided ports/checksum are
+/// expanded and passed through `rebuild_udp_header` so the datagram this
+/// produces is actually parseable as UDP, not just labeled as one.
+fn decode_nhc_udp(data: &[u8], mut off: usize) -> Option<(u8, Vec<u8>)> {
+    let nhc = *data.get(off)?;
+    if nhc >> 3 != 0b11110 {
+        // not LOWPAN_NHC UDP; leave as-is (unsupported NHC types are not
+        // expanded further)
+        return Some((17, data[off..].to_vec()));
+    }
+    off += 1;
+    let checksum_elided = nhc & 0x4 != 0;
+    let ports_form = nhc & 0x3;
+
+    let (src_port, dst_port) = match ports_form {
+        0b00 => {
+            if data.len() < off + 4 {
+                return None;
+            }
+            let s = u16::from_be_bytes([data[off], data[off + 1]]);
+            let d = u16::from_be_bytes([data[off + 2], data[off + 3]]);
+            off += 4;
+            (s, d)
+        }
+        0b01 => {
+            if data.len() < off + 3 {
+                return None;
+            }
+            let s = u16::from_be_bytes([data[off], data[off + 1]]);
+            let d = 0xf000 | u16::from(data[off + 2]);
+            off += 3;
+            (s, d)
+        }
+        0b10 => {
+            if data.len() < off + 3 {
+                return None;
+            }
+            let s = 0xf000 | u16::from(data[off]);
+            let d = u16::from_be_bytes([data[off + 1], data[off + 2]]);
+            off += 3;
+            (s, d)
+        }
+        0b11 => {
+            if data.len() < off + 1 {
+                return None;
+            }
+            let s = 0xf0b0 | u16::from(data[off] >> 4);
+            let d = 0xf0b0 | u16::from(data[off] & 0xf);
+            off += 1;
+            (s, d)
+        }
+        _ => unreachable!(),
+    };
+
+    let checksum = if checksum_elided {
+        0u16
+    } else {
+        if data.len() < off + 2 {
+            return None;
+        }
+        let c = u16::from_be_bytes([data[off], data[off + 1]]);
+        off += 2;
+        c
+    };
+
+    let udp_payload = &data[off..];
+    Some((17, rebuild_udp_header(src_port, dst_port, checksum, udp_payload)))
+}
+
+/// Rebuild the 8-byte UDP header elided by LOWPAN_NHC, given the decoded
+/// ports/checksum and the UDP payload that follows.
+pub fn rebuild_udp_header(src_port: u16, dst_port: u16, checksum: u16, payload: &[u8]) -> Vec<u8> {
+    let mut out = Vec::with_capacity(8 + payload.len());
+    out.extend_from_slice(&src_port.to_be_bytes());
+    out.extend_from_slice(&dst_port.to_be_bytes());
+    let len = (8 + payload.len()) as u16;
+    out.extend_from_slice(&len.to_be_bytes());
+    out.extend_from_slice(&checksum.to_be_bytes());
+    out.extend_from_slice(payload);
+    out
+}
+
+const DISPATCH_FRAG1: u8 = 0b11000;
+const DISPATCH_FRAGN: u8 = 0b11100;
+
+#[derive(Debug, Eq, PartialEq)]
+pub enum SixLowPanFragment<'a> {
+    /// Frame was not a fragment; `data` is the IPHC-compressed payload.
+    NoFrag(&'a [u8]),
+    /// Last fragment received: the full reassembled datagram.
+    Complete(Vec<u8>),
+    /// Fragment stored, datagram not yet complete.
+    Incomplete,
+    Error,
+}
+
+struct PartialDatagram {
+    size: usize,
+    received: usize,
+    buf: Vec<u8>,
+    have: Vec<bool>,
+}
+
+/// Reassembles 6LoWPAN fragments (RFC 4944 5.3), keyed by
+/// `(src_pan_id, src_addr, datagram_tag)`.
+#[derive(Default)]
+pub struct SixLowPanDefrag {
+    datagrams: HashMap<(u16, u64, u16), PartialDatagram>,
+}
+
+impl SixLowPanDefrag {
+    /// Feed a single 802.15.4 payload through the defrag engine. `data`
+    /// must start at the 6LoWPAN dispatch byte.
+    pub fn update<'a>(
+        &mut self,
+        pan_id: u16,
+        src_addr: u64,
+        data: &'a [u8],
+    ) -> SixLowPanFragment<'a> {
+        if data.is_empty() {
+            return SixLowPanFragment::Error;
+        }
+        let dispatch = data[0] >> 3;
+        if dispatch != DISPATCH_FRAG1 && dispatch != DISPATCH_FRAGN {
+            return SixLowPanFragment::NoFrag(data);
+        }
+        if data.len() < 4 {
+            return SixLowPanFragment::Error;
+        }
+        let datagram_size = (u16::from(data[0] & 0x7) << 8 | u16::from(data[1])) as usize;
+        let datagram_tag = u16::from_be_bytes([data[2], data[3]]);
+
+        let (offset, payload) = if dispatch == DISPATCH_FRAG1 {
+            (0usize, &data[4..])
+        } else {
+            if data.len() < 5 {
+                return SixLowPanFragment::Error;
+            }
+            ((data[4] as usize) * 8, &data[5..])
+        };
+
+        let key = (pan_id, src_addr, datagram_tag);
+        let entry = self.datagrams.entry(key).or_insert_with(|| PartialDatagram {
+            size: datagram_size,
+            received: 0,
+            buf: vec![0; datagram_size],
+            have: vec![false; datagram_size],
+        });
+
+        if entry.size != datagram_size || offset + payload.len() > entry.size {
+            self.datagrams.remove(&key);
+            return SixLowPanFragment::Error;
+        }
+
+        for (i, &b) in payload.iter().enumerate() {
+            if !entry.have[offset + i] {
+                entry.have[offset + i] = true;
+                entry.received += 1;
+            }
+            entry.buf[offset + i] = b;
+        }
+
+        if entry.received == entry.size {
+            let complete = self.datagrams.remove(&key).unwrap();
+            SixLowPanFragment::Complete(complete.buf)
+        } else {
+            SixLowPanFragment::Incomplete
+        }
+    }
+}
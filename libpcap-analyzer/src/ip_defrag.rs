@@ -0,0 +1,334 @@
+use libpcap_tools::{Config, Duration};
+use std::collections::HashMap;
+
+/// Default idle timeout before a half-assembled datagram is dropped.
+const DEFAULT_TIMEOUT_SECS: u64 = 60;
+/// Default ceiling on bytes buffered across all in-flight datagrams for a
+/// single engine instance (one instance per IP version).
+const DEFAULT_MAX_TOTAL_BYTES: usize = 8 * 1024 * 1024;
+
+/// Outcome of feeding one fragment into a `DefragEngine`.
+pub enum Fragment<'a> {
+    /// The datagram was not fragmented; `data` is the original slice,
+    /// untouched.
+    NoFrag(&'a [u8]),
+    /// This fragment completed the datagram; the hole-free payload.
+    Complete(Vec<u8>),
+    /// Still waiting on more fragments (or a gap to be filled).
+    Incomplete,
+    /// The fragment was rejected: a disallowed overlap, a buffer that
+    /// can't fit within the memory ceiling, or a malformed datagram.
+    Error,
+}
+
+/// How overlapping IPv4 fragments are resolved. Different target OSes
+/// disagree here, which matters for modelling fragmentation-based IDS
+/// evasion. IPv6 has no equivalent knob: RFC 5722 mandates dropping the
+/// whole datagram if any of its fragments overlap.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum OverlapPolicy {
+    /// Keep the bytes from whichever fragment covering a given offset
+    /// arrived first.
+    FirstWins,
+    /// Keep the bytes from whichever fragment covering a given offset
+    /// arrived last, overwriting earlier data.
+    LastWins,
+}
+
+/// Per-IP-version fragment reassembly, keyed by datagram identification.
+pub trait DefragEngine {
+    /// Feed one fragment of datagram `id`, covering `[frag_offset,
+    /// frag_offset + data.len())` of the final payload. `now` is the
+    /// packet's own timestamp (not wall-clock), so that idle-timeout
+    /// expiry is correct for offline replays as well as live captures.
+    fn update<'a>(
+        &mut self,
+        id: u32,
+        frag_offset: usize,
+        more_fragments: bool,
+        data: &'a [u8],
+        now: Duration,
+    ) -> Fragment<'a>;
+
+    /// Reporting counters: `(timeouts, overlaps_dropped, evictions)`.
+    fn stats(&self) -> (usize, usize, usize) {
+        (0, 0, 0)
+    }
+}
+
+/// A non-overlapping byte range already written into a partial datagram's
+/// buffer, used as the hole list: the datagram is complete once a single
+/// range covers `[0, total_len)`.
+#[derive(Clone, Copy, Debug)]
+struct Range {
+    start: usize,
+    end: usize,
+}
+
+struct PartialDatagram {
+    buf: Vec<u8>,
+    /// Merged, non-overlapping ranges of `buf` that hold real fragment
+    /// data, sorted by `start`.
+    received: Vec<Range>,
+    /// Total datagram length, known once the last fragment (`more_fragments
+    /// == false`) has been seen.
+    total_len: Option<usize>,
+    last_seen: Duration,
+}
+
+impl PartialDatagram {
+    fn new(now: Duration) -> Self {
+        PartialDatagram {
+            buf: Vec::new(),
+            received: Vec::new(),
+            total_len: None,
+            last_seen: now,
+        }
+    }
+
+    fn is_complete(&self) -> bool {
+        match self.total_len {
+            Some(len) => {
+                self.received.len() == 1 && self.received[0].start == 0 && self.received[0].end == len
+            }
+            None => false,
+        }
+    }
+
+    fn overlaps(&self, start: usize, end: usize) -> bool {
+        self.received.iter().any(|r| start < r.end && r.start < end)
+    }
+
+    /// Sub-ranges of `[start, end)` not yet covered by `received`.
+    fn gaps_in(&self, start: usize, end: usize) -> Vec<(usize, usize)> {
+        let mut gaps = vec![(start, end)];
+        for r in &self.received {
+            let mut next = Vec::with_capacity(gaps.len());
+            for (s, e) in gaps {
+                if r.end <= s || r.start >= e {
+                    next.push((s, e));
+                    continue;
+                }
+                if r.start > s {
+                    next.push((s, r.start));
+                }
+                if r.end < e {
+                    next.push((r.end, e));
+                }
+            }
+            gaps = next;
+        }
+        gaps
+    }
+
+    fn mark_received(&mut self, start: usize, end: usize) {
+        self.received.push(Range { start, end });
+        self.received.sort_by_key(|r| r.start);
+        let mut merged: Vec<Range> = Vec::with_capacity(self.received.len());
+        for r in self.received.drain(..) {
+            match merged.last_mut() {
+                Some(last) if r.start <= last.end => last.end = last.end.max(r.end),
+                _ => merged.push(r),
+            }
+        }
+        self.received = merged;
+    }
+}
+
+/// RFC-aware IP fragment reassembly with idle-timeout expiry, a
+/// total-memory ceiling (LRU-evicting the oldest in-flight datagrams
+/// before rejecting a fragment outright), and an overlap policy.
+pub struct IPDefragEngine {
+    datagrams: HashMap<u32, PartialDatagram>,
+    timeout: Duration,
+    max_total_bytes: usize,
+    total_bytes: usize,
+    /// `None` selects IPv6 semantics (RFC 5722: drop the whole datagram on
+    /// overlap); `Some(_)` selects the configurable IPv4 behavior.
+    overlap_policy: Option<OverlapPolicy>,
+
+    pub timeouts: usize,
+    pub overlaps_dropped: usize,
+    pub evictions: usize,
+}
+
+impl IPDefragEngine {
+    fn new(overlap_policy: Option<OverlapPolicy>, timeout: Duration, max_total_bytes: usize) -> Self {
+        IPDefragEngine {
+            datagrams: HashMap::new(),
+            timeout,
+            max_total_bytes,
+            total_bytes: 0,
+            overlap_policy,
+            timeouts: 0,
+            overlaps_dropped: 0,
+            evictions: 0,
+        }
+    }
+
+    /// Build the IPv4 reassembly engine, reading `ipv4_defrag_timeout_secs`,
+    /// `ipv4_defrag_max_bytes` and `ipv4_defrag_overlap_policy` (`"first_wins"`
+    /// or `"last_wins"`, default `first_wins`) from `config`.
+    pub fn new_ipv4(config: &Config) -> Self {
+        let policy = match config.get("ipv4_defrag_overlap_policy") {
+            Some("last_wins") => OverlapPolicy::LastWins,
+            _ => OverlapPolicy::FirstWins,
+        };
+        Self::new(
+            Some(policy),
+            Duration::new(
+                config
+                    .get_usize("ipv4_defrag_timeout_secs")
+                    .unwrap_or(DEFAULT_TIMEOUT_SECS as usize) as u64,
+                0,
+            ),
+            config
+                .get_usize("ipv4_defrag_max_bytes")
+                .unwrap_or(DEFAULT_MAX_TOTAL_BYTES),
+        )
+    }
+
+    /// Build the IPv6 reassembly engine, reading `ipv6_defrag_timeout_secs`
+    /// and `ipv6_defrag_max_bytes` from `config`. Overlapping fragments are
+    /// always rejected per RFC 5722, so there is no overlap-policy knob.
+    pub fn new_ipv6(config: &Config) -> Self {
+        Self::new(
+            None,
+            Duration::new(
+                config
+                    .get_usize("ipv6_defrag_timeout_secs")
+                    .unwrap_or(DEFAULT_TIMEOUT_SECS as usize) as u64,
+                0,
+            ),
+            config
+                .get_usize("ipv6_defrag_max_bytes")
+                .unwrap_or(DEFAULT_MAX_TOTAL_BYTES),
+        )
+    }
+
+    fn expire_stale(&mut self, now: Duration) {
+        let expired: Vec<u32> = self
+            .datagrams
+            .iter()
+            .filter(|(_, d)| now >= d.last_seen && now - d.last_seen > self.timeout)
+            .map(|(id, _)| *id)
+            .collect();
+        for id in expired {
+            if let Some(d) = self.datagrams.remove(&id) {
+                warn!("IP defrag: datagram id={:x} expired after timeout", id);
+                self.total_bytes -= d.buf.len();
+                self.timeouts += 1;
+            }
+        }
+    }
+
+    /// Evict the oldest in-flight datagrams (other than `keep_id`) until
+    /// `extra_bytes` more would fit within `max_total_bytes`, returning
+    /// `false` if it still wouldn't fit once everything else is gone.
+    fn make_room(&mut self, keep_id: u32, extra_bytes: usize) -> bool {
+        if self.total_bytes + extra_bytes <= self.max_total_bytes {
+            return true;
+        }
+        let mut candidates: Vec<(u32, Duration)> = self
+            .datagrams
+            .iter()
+            .filter(|(id, _)| **id != keep_id)
+            .map(|(id, d)| (*id, d.last_seen))
+            .collect();
+        candidates.sort_by_key(|(_, last_seen)| *last_seen);
+        for (id, _) in candidates {
+            if self.total_bytes + extra_bytes <= self.max_total_bytes {
+                break;
+            }
+            if let Some(d) = self.datagrams.remove(&id) {
+                warn!("IP defrag: evicting datagram id={:x} to stay under memory cap", id);
+                self.total_bytes -= d.buf.len();
+                self.evictions += 1;
+            }
+        }
+        self.total_bytes + extra_bytes <= self.max_total_bytes
+    }
+}
+
+impl DefragEngine for IPDefragEngine {
+    fn update<'a>(
+        &mut self,
+        id: u32,
+        frag_offset: usize,
+        more_fragments: bool,
+        data: &'a [u8],
+        now: Duration,
+    ) -> Fragment<'a> {
+        if frag_offset == 0 && !more_fragments {
+            return Fragment::NoFrag(data);
+        }
+
+        self.expire_stale(now);
+
+        let start = frag_offset;
+        let end = frag_offset + data.len();
+
+        // Enforce the memory cap against this fragment's actual contribution
+        // to the datagram's buffer size (`end - prev_len`, which can be far
+        // larger than `data.len()` thanks to `frag_offset`), on every
+        // fragment -- not just the one that starts a new datagram id, or a
+        // single big `frag_offset` on a later fragment could grow an
+        // already-tracked datagram past `max_total_bytes` unchecked.
+        let existing_len = self.datagrams.get(&id).map_or(0, |d| d.buf.len());
+        let growth = end.saturating_sub(existing_len);
+        if growth > 0 && !self.make_room(id, growth) {
+            warn!("IP defrag: no room for fragment of datagram id={:x}, dropping fragment", id);
+            return Fragment::Error;
+        }
+
+        let entry = self
+            .datagrams
+            .entry(id)
+            .or_insert_with(|| PartialDatagram::new(now));
+        entry.last_seen = now;
+
+        let overlapped = entry.overlaps(start, end);
+        if overlapped {
+            self.overlaps_dropped += 1;
+            if self.overlap_policy.is_none() {
+                // IPv6: RFC 5722 mandates dropping the whole datagram.
+                warn!("IPv6 defrag: overlapping fragments for id={:x}, dropping datagram", id);
+                if let Some(d) = self.datagrams.remove(&id) {
+                    self.total_bytes -= d.buf.len();
+                }
+                return Fragment::Error;
+            }
+        }
+
+        let prev_len = entry.buf.len();
+        if entry.buf.len() < end {
+            entry.buf.resize(end, 0);
+        }
+        self.total_bytes += entry.buf.len() - prev_len;
+
+        if overlapped && self.overlap_policy == Some(OverlapPolicy::FirstWins) {
+            for (s, e) in entry.gaps_in(start, end) {
+                entry.buf[s..e].copy_from_slice(&data[s - start..e - start]);
+            }
+        } else {
+            entry.buf[start..end].copy_from_slice(data);
+        }
+        entry.mark_received(start, end);
+
+        if !more_fragments {
+            entry.total_len = Some(end);
+        }
+
+        if entry.is_complete() {
+            let d = self.datagrams.remove(&id).expect("datagram vanished");
+            self.total_bytes -= d.buf.len();
+            Fragment::Complete(d.buf)
+        } else {
+            Fragment::Incomplete
+        }
+    }
+
+    fn stats(&self) -> (usize, usize, usize) {
+        (self.timeouts, self.overlaps_dropped, self.evictions)
+    }
+}
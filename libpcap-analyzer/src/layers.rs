@@ -0,0 +1,8 @@
+/// Link-layer kind a packet (or reconstructed sub-packet) was seen on,
+/// used as the layer-2 filter key when dispatching to plugins.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+#[repr(u16)]
+pub enum LinkLayerType {
+    Ethernet = 1,
+    Ieee802154 = 2,
+}
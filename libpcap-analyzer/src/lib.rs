@@ -3,9 +3,12 @@
 #[macro_use]
 extern crate log;
 
+extern crate cookie_factory;
+extern crate crossbeam_channel;
 extern crate nom;
 extern crate pcap_parser;
 extern crate rand;
+extern crate rusqlite;
 
 mod flow_map;
 mod packet_info;
@@ -13,8 +16,15 @@ pub use flow_map::FlowMap;
 
 mod plugin;
 #[macro_use] mod plugin_registry;
+mod plugin_stats;
+mod rewrite;
 pub use plugin::*;
 pub use plugin_registry::*;
+pub use plugin_stats::{PluginID, PluginStatsSummary};
+pub use rewrite::{
+    ipv4_pseudo_header_sum, ipv6_pseudo_header_sum, EnclosingIp, Ipv4Header, Ipv6Header,
+    RewriteWriter, RewrittenLayer, TcpHeader, UdpHeader,
+};
 
 pub mod plugins;
 pub mod output;
@@ -25,12 +35,21 @@ pub use analyzer::*;
 pub use threaded_analyzer::*;
 
 mod erspan;
+mod external_plugin;
+mod flow_expiration;
+mod geneve;
+mod ieee802154;
 mod ip6_defrag;
 mod ip_defrag;
+mod layers;
 mod tcp_reassembly;
 mod vxlan;
+pub use external_plugin::{register_external_plugin, ExternalPluginProxy};
 pub use erspan::*;
+pub use geneve::*;
+pub use ieee802154::*;
 pub use ip6_defrag::*;
+pub use layers::*;
 pub use vxlan::*;
 
 pub mod toeplitz;
@@ -0,0 +1,38 @@
+//! Output sinks for data the analyzer produces outside the normal plugin
+//! dispatch path (as opposed to `rewrite.rs`'s `RewriteWriter`, which is
+//! driven directly by `PluginResult::Rewrite`).
+
+mod netflow9;
+mod record_writer;
+mod sqlite_sink;
+pub use netflow9::FlowExporter;
+pub use record_writer::RecordWriter;
+pub use sqlite_sink::SqliteSink;
+
+use libpcap_tools::{Error, Flow, FlowID};
+
+/// One row of data handed to a `RecordWriter`'s background thread. Analyzer
+/// code only ever constructs these and sends them; it never touches the
+/// `RecordSink` that ends up committing them.
+#[derive(Clone, Debug)]
+pub enum OutputRecord {
+    /// A flow's final (or, for a still-open flow, cumulative-so-far)
+    /// counters -- the same data `FlowExporter` turns into a NetFlow v9
+    /// record, but destined for a queryable persistent store instead.
+    Flow(Flow),
+    /// A one-off event tied to a flow (e.g. a plugin-detected anomaly),
+    /// for stores that want more than just flow counters.
+    Event {
+        flow_id: FlowID,
+        kind: &'static str,
+        message: String,
+    },
+}
+
+/// Implemented by a persistent store a `RecordWriter`'s background thread
+/// commits batches of `OutputRecord`s into. Analogous to the `ExportSink`
+/// split in `netflow9`, but for a store that can be queried after the fact
+/// rather than streamed to a collector.
+pub trait RecordSink: Send {
+    fn write_batch(&mut self, records: &[OutputRecord]) -> Result<(), Error>;
+}
@@ -0,0 +1,267 @@
+//! NetFlow v9 (RFC 3954) flow-record exporter: a template FlowSet declaring
+//! the fields below, followed by a data FlowSet with one record per
+//! direction of a flow that had any traffic, written either to a file (one
+//! NetFlow v9 packet appended after another) or sent over UDP to a
+//! collector.
+//!
+//! Exports happen from two places in `analyzer.rs`: `sweep_expired_flows`/
+//! `teardown` (the flow is gone, final counters) and `maybe_export_active_flow`
+//! (the flow is still open but has been active longer than
+//! `flow_active_timeout_secs`, so it's exported periodically rather than
+//! only at the end). The active-timeout records report the flow's
+//! cumulative counters rather than a per-interval delta, so a collector
+//! that only keeps the latest record per flow still sees the right totals.
+
+use cookie_factory::bytes::{be_u16, be_u32, be_u8};
+use cookie_factory::gen_simple;
+use cookie_factory::sequence::tuple;
+use libpcap_tools::{Config, Duration, Flow, FlowID};
+use std::collections::HashMap;
+use std::fs::{File, OpenOptions};
+use std::io;
+use std::io::Write;
+use std::net::{IpAddr, UdpSocket};
+
+const NETFLOW_VERSION: u16 = 9;
+const TEMPLATE_FLOWSET_ID: u16 = 0;
+const TEMPLATE_ID_V4: u16 = 256;
+const TEMPLATE_ID_V6: u16 = 257;
+
+// NetFlow v9 field types (RFC 3954 §8).
+const FIELD_IN_BYTES: u16 = 1;
+const FIELD_IN_PKTS: u16 = 2;
+const FIELD_PROTOCOL: u16 = 4;
+const FIELD_TCP_FLAGS: u16 = 6;
+const FIELD_L4_SRC_PORT: u16 = 7;
+const FIELD_IPV4_SRC_ADDR: u16 = 8;
+const FIELD_L4_DST_PORT: u16 = 11;
+const FIELD_IPV4_DST_ADDR: u16 = 12;
+const FIELD_LAST_SWITCHED: u16 = 21;
+const FIELD_FIRST_SWITCHED: u16 = 22;
+const FIELD_IPV6_SRC_ADDR: u16 = 27;
+const FIELD_IPV6_DST_ADDR: u16 = 28;
+
+/// `(field_type, field_length)` pairs, in wire order, shared by both the
+/// template and its data records.
+fn template_fields(addr_len: u16, src_addr_field: u16, dst_addr_field: u16) -> [(u16, u16); 10] {
+    [
+        (src_addr_field, addr_len),
+        (dst_addr_field, addr_len),
+        (FIELD_L4_SRC_PORT, 2),
+        (FIELD_L4_DST_PORT, 2),
+        (FIELD_PROTOCOL, 1),
+        (FIELD_TCP_FLAGS, 1),
+        (FIELD_IN_PKTS, 4),
+        (FIELD_IN_BYTES, 4),
+        (FIELD_FIRST_SWITCHED, 4),
+        (FIELD_LAST_SWITCHED, 4),
+    ]
+}
+
+enum ExportSink {
+    File(File),
+    Udp(UdpSocket),
+}
+
+impl ExportSink {
+    fn write(&mut self, bytes: &[u8]) -> io::Result<()> {
+        match self {
+            ExportSink::File(f) => f.write_all(bytes),
+            ExportSink::Udp(s) => s.send(bytes).map(|_| ()),
+        }
+    }
+}
+
+/// Exports `Flow`s as NetFlow v9 records, reading `netflow_collector_addr`
+/// (`host:port`, preferred) or `netflow_output_path` from `config`; `None`
+/// if neither is set, so callers that never export pay nothing.
+pub struct FlowExporter {
+    sink: ExportSink,
+    source_id: u32,
+    sequence: u32,
+    /// When each flow was last exported as an active-timeout "still open"
+    /// record, so `maybe_export_active_flow` doesn't re-export on every
+    /// packet once a flow has crossed the active timeout.
+    last_active_export: HashMap<FlowID, Duration>,
+}
+
+impl FlowExporter {
+    pub fn from_config(config: &Config) -> Option<Self> {
+        let sink = if let Some(addr) = config.get("netflow_collector_addr") {
+            match UdpSocket::bind("0.0.0.0:0").and_then(|s| s.connect(addr).map(|()| s)) {
+                Ok(s) => ExportSink::Udp(s),
+                Err(e) => {
+                    warn!("could not connect to netflow collector {}: {}", addr, e);
+                    return None;
+                }
+            }
+        } else if let Some(path) = config.get("netflow_output_path") {
+            match OpenOptions::new().create(true).append(true).open(path) {
+                Ok(f) => ExportSink::File(f),
+                Err(e) => {
+                    warn!("could not open netflow output {}: {}", path, e);
+                    return None;
+                }
+            }
+        } else {
+            return None;
+        };
+        Some(FlowExporter {
+            sink,
+            source_id: 0,
+            sequence: 0,
+            last_active_export: HashMap::new(),
+        })
+    }
+
+    /// Whether `flow_id` is due for an active-timeout re-export: it hasn't
+    /// been exported yet (in which case `flow.first_seen` is the baseline)
+    /// or it's been at least `active_timeout` since its last export.
+    pub fn active_export_due(&self, flow_id: FlowID, flow: &Flow, now: Duration, active_timeout: Duration) -> bool {
+        let baseline = self.last_active_export.get(&flow_id).copied().unwrap_or(flow.first_seen);
+        now.secs.saturating_sub(baseline.secs) >= active_timeout.secs
+    }
+
+    pub fn mark_active_export(&mut self, flow_id: FlowID, now: Duration) {
+        self.last_active_export.insert(flow_id, now);
+    }
+
+    /// Stop tracking `flow_id` (it's been destroyed and just received its
+    /// final export).
+    pub fn forget(&mut self, flow_id: FlowID) {
+        self.last_active_export.remove(&flow_id);
+    }
+
+    /// Export `flow`: one data record per direction that saw any traffic.
+    /// No-op if the flow is otherwise empty (e.g. exported right after
+    /// creation, before any packet was counted towards it).
+    pub fn export_flow(&mut self, flow: &Flow) {
+        let mut records = Vec::new();
+        if flow.packets_toserver > 0 {
+            records.push(build_data_record(flow, true));
+        }
+        if flow.packets_toclient > 0 {
+            records.push(build_data_record(flow, false));
+        }
+        if records.is_empty() {
+            return;
+        }
+        let is_v6 = matches!(flow.five_tuple.src, IpAddr::V6(_));
+        let template_id = if is_v6 { TEMPLATE_ID_V6 } else { TEMPLATE_ID_V4 };
+        let packet = self.build_packet(template_id, is_v6, &records);
+        if let Err(e) = self.sink.write(&packet) {
+            warn!("netflow export failed: {}", e);
+        }
+        self.sequence += 1;
+    }
+
+    fn build_packet(&self, template_id: u16, is_v6: bool, records: &[Vec<u8>]) -> Vec<u8> {
+        let addr_len = if is_v6 { 16 } else { 4 };
+        let (src_field, dst_field) = if is_v6 {
+            (FIELD_IPV6_SRC_ADDR, FIELD_IPV6_DST_ADDR)
+        } else {
+            (FIELD_IPV4_SRC_ADDR, FIELD_IPV4_DST_ADDR)
+        };
+        let fields = template_fields(addr_len, src_field, dst_field);
+
+        let template_record_len = 4 + fields.len() * 4; // template_id + field_count + fields
+        let template_flowset_len = 4 + template_record_len; // flowset header + one record
+        let mut template_flowset = gen_simple(
+            tuple((be_u16(TEMPLATE_FLOWSET_ID), be_u16(template_flowset_len as u16))),
+            Vec::new(),
+        )
+        .expect("writing to a Vec<u8> cannot fail");
+        template_flowset.extend_from_slice(
+            &gen_simple(
+                tuple((be_u16(template_id), be_u16(fields.len() as u16))),
+                Vec::new(),
+            )
+            .expect("writing to a Vec<u8> cannot fail"),
+        );
+        for (field_type, field_len) in fields {
+            template_flowset.extend_from_slice(
+                &gen_simple(tuple((be_u16(field_type), be_u16(field_len))), Vec::new())
+                    .expect("writing to a Vec<u8> cannot fail"),
+            );
+        }
+
+        let data_len: usize = records.iter().map(Vec::len).sum();
+        let data_flowset_len = 4 + data_len;
+        let mut data_flowset = gen_simple(
+            tuple((be_u16(template_id), be_u16(data_flowset_len as u16))),
+            Vec::new(),
+        )
+        .expect("writing to a Vec<u8> cannot fail");
+        for record in records {
+            data_flowset.extend_from_slice(record);
+        }
+
+        let count = 2u16; // one template flowset + one data flowset
+        let mut packet = gen_simple(
+            tuple((
+                be_u16(NETFLOW_VERSION),
+                be_u16(count),
+                be_u32(0), // sys_uptime: not tracked by this analyzer
+                be_u32(0), // unix_secs: packet timestamps are relative to the capture, not wall-clock
+                be_u32(self.sequence),
+                be_u32(self.source_id),
+            )),
+            Vec::new(),
+        )
+        .expect("writing to a Vec<u8> cannot fail");
+        packet.extend_from_slice(&template_flowset);
+        packet.extend_from_slice(&data_flowset);
+        packet
+    }
+}
+
+fn build_data_record(flow: &Flow, to_server: bool) -> Vec<u8> {
+    let (src, dst, src_port, dst_port, pkts, bytes) = if to_server {
+        (
+            flow.five_tuple.src,
+            flow.five_tuple.dst,
+            flow.five_tuple.src_port,
+            flow.five_tuple.dst_port,
+            flow.packets_toserver,
+            flow.bytes_toserver,
+        )
+    } else {
+        (
+            flow.five_tuple.dst,
+            flow.five_tuple.src,
+            flow.five_tuple.dst_port,
+            flow.five_tuple.src_port,
+            flow.packets_toclient,
+            flow.bytes_toclient,
+        )
+    };
+    let mut buf = Vec::new();
+    match src {
+        IpAddr::V4(v4) => buf.extend_from_slice(&v4.octets()),
+        IpAddr::V6(v6) => buf.extend_from_slice(&v6.octets()),
+    }
+    match dst {
+        IpAddr::V4(v4) => buf.extend_from_slice(&v4.octets()),
+        IpAddr::V6(v6) => buf.extend_from_slice(&v6.octets()),
+    }
+    buf.extend_from_slice(
+        &gen_simple(
+            tuple((
+                be_u16(src_port),
+                be_u16(dst_port),
+                be_u8(flow.five_tuple.proto),
+                be_u8(flow.tcp_flags),
+                // A flow can run far longer, and carry far more traffic,
+                // than a u32 counter can hold (the field width RFC 3954
+                // mandates); saturate rather than silently wrap.
+                be_u32(u32::try_from(pkts).unwrap_or(u32::MAX)),
+                be_u32(u32::try_from(bytes).unwrap_or(u32::MAX)),
+                be_u32(flow.first_seen.secs as u32),
+                be_u32(flow.last_seen.secs as u32),
+            )),
+            Vec::new(),
+        )
+        .expect("writing to a Vec<u8> cannot fail"),
+    );
+    buf
+}
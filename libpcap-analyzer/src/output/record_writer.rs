@@ -0,0 +1,81 @@
+//! Streams `OutputRecord`s to a `RecordSink` from a single background
+//! thread, so committing them to a persistent store never blocks whichever
+//! analyzer thread(s) produced them -- they only ever call `send`, which is
+//! a channel push, not I/O.
+
+use crate::output::{OutputRecord, RecordSink};
+use crossbeam_channel::{bounded, Sender};
+use std::thread::{self, JoinHandle};
+
+/// Owns the writer thread and the sending half of its channel. Cloning
+/// `sender()` and handing the clones to multiple analyzer workers (see
+/// `ThreadedAnalyzer`) fans their records into this one writer, so records
+/// from every worker land in the same transaction-batched stream instead
+/// of each worker needing its own store/connection.
+pub struct RecordWriter {
+    tx: Sender<OutputRecord>,
+    handle: JoinHandle<usize>,
+}
+
+impl RecordWriter {
+    /// Spawns the writer thread, committing to `sink` every `batch_size`
+    /// records (and once more, for whatever's left, when every `Sender`
+    /// clone has been dropped and the channel closes).
+    pub fn spawn(mut sink: Box<dyn RecordSink>, batch_size: usize) -> Self {
+        let batch_size = batch_size.max(1);
+        let (tx, rx) = bounded::<OutputRecord>(batch_size * 4);
+        let handle = thread::spawn(move || {
+            let mut total = 0usize;
+            let mut batch = Vec::with_capacity(batch_size);
+            while let Ok(record) = rx.recv() {
+                batch.push(record);
+                if batch.len() >= batch_size {
+                    total += flush(sink.as_mut(), &mut batch);
+                }
+            }
+            total += flush(sink.as_mut(), &mut batch);
+            total
+        });
+        RecordWriter { tx, handle }
+    }
+
+    /// A clone of the sending half, for another analyzer worker to send
+    /// its own records into this same writer.
+    pub fn sender(&self) -> Sender<OutputRecord> {
+        self.tx.clone()
+    }
+
+    /// Queue `record` for the writer thread. Never touches I/O itself;
+    /// only blocks (briefly) if the writer is behind and the channel's
+    /// bounded buffer is full.
+    pub fn send(&self, record: OutputRecord) {
+        if self.tx.send(record).is_err() {
+            warn!("record writer thread is gone, dropping record");
+        }
+    }
+
+    /// Drops this writer's `Sender`, then waits for the writer thread to
+    /// flush whatever's pending and exit, returning the total number of
+    /// records it committed. Callers that cloned `sender()` out to other
+    /// workers must drop those too (or this blocks forever waiting for the
+    /// channel to close).
+    pub fn join(self) -> usize {
+        drop(self.tx);
+        self.handle.join().unwrap_or_else(|_| {
+            warn!("record writer thread panicked");
+            0
+        })
+    }
+}
+
+fn flush(sink: &mut dyn RecordSink, batch: &mut Vec<OutputRecord>) -> usize {
+    if batch.is_empty() {
+        return 0;
+    }
+    let n = batch.len();
+    if let Err(e) = sink.write_batch(batch) {
+        warn!("record sink write failed: {:?}", e);
+    }
+    batch.clear();
+    n
+}
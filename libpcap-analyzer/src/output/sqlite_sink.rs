@@ -0,0 +1,92 @@
+//! `RecordSink` backed by SQLite (via `rusqlite`), the first persistent
+//! store a `RecordWriter` can commit batches into. Every call happens on
+//! the writer thread, never on an analyzer thread.
+
+use crate::output::{OutputRecord, RecordSink};
+use libpcap_tools::Error;
+use rusqlite::{params, Connection};
+use std::net::IpAddr;
+
+pub struct SqliteSink {
+    conn: Connection,
+}
+
+impl SqliteSink {
+    /// Opens (creating if needed) the database at `path` and ensures its
+    /// schema exists.
+    pub fn open(path: &str) -> Result<Self, Error> {
+        let conn = Connection::open(path)
+            .map_err(|_| Error::Generic("could not open sqlite output database"))?;
+        conn.execute_batch(
+            "CREATE TABLE IF NOT EXISTS flows (
+                flow_id          INTEGER NOT NULL,
+                src              TEXT NOT NULL,
+                dst              TEXT NOT NULL,
+                src_port         INTEGER NOT NULL,
+                dst_port         INTEGER NOT NULL,
+                proto            INTEGER NOT NULL,
+                packets_toserver INTEGER NOT NULL,
+                bytes_toserver   INTEGER NOT NULL,
+                packets_toclient INTEGER NOT NULL,
+                bytes_toclient   INTEGER NOT NULL,
+                tcp_flags        INTEGER NOT NULL
+            );
+            CREATE TABLE IF NOT EXISTS events (
+                flow_id INTEGER NOT NULL,
+                kind    TEXT NOT NULL,
+                message TEXT NOT NULL
+            );",
+        )
+        .map_err(|_| Error::Generic("could not create sqlite output schema"))?;
+        Ok(SqliteSink { conn })
+    }
+}
+
+impl RecordSink for SqliteSink {
+    fn write_batch(&mut self, records: &[OutputRecord]) -> Result<(), Error> {
+        let tx = self
+            .conn
+            .transaction()
+            .map_err(|_| Error::Generic("could not start sqlite transaction"))?;
+        for record in records {
+            let result = match record {
+                OutputRecord::Flow(flow) => tx.execute(
+                    "INSERT INTO flows (
+                        flow_id, src, dst, src_port, dst_port, proto,
+                        packets_toserver, bytes_toserver,
+                        packets_toclient, bytes_toclient, tcp_flags
+                    ) VALUES (?1, ?2, ?3, ?4, ?5, ?6, ?7, ?8, ?9, ?10, ?11)",
+                    params![
+                        flow.flow_id as i64,
+                        format_addr(flow.five_tuple.src),
+                        format_addr(flow.five_tuple.dst),
+                        flow.five_tuple.src_port,
+                        flow.five_tuple.dst_port,
+                        flow.five_tuple.proto,
+                        flow.packets_toserver as i64,
+                        flow.bytes_toserver as i64,
+                        flow.packets_toclient as i64,
+                        flow.bytes_toclient as i64,
+                        flow.tcp_flags,
+                    ],
+                ),
+                OutputRecord::Event {
+                    flow_id,
+                    kind,
+                    message,
+                } => tx.execute(
+                    "INSERT INTO events (flow_id, kind, message) VALUES (?1, ?2, ?3)",
+                    params![*flow_id as i64, kind, message],
+                ),
+            };
+            result.map_err(|_| Error::Generic("sqlite insert failed"))?;
+        }
+        tx.commit()
+            .map_err(|_| Error::Generic("could not commit sqlite transaction"))?;
+        Ok(())
+    }
+}
+
+fn format_addr(addr: IpAddr) -> String {
+    addr.to_string()
+}
@@ -0,0 +1,41 @@
+use libpcap_tools::{Flow, FiveTuple, FlowID};
+
+/// Reason an ICMP/ICMPv6 message quoting another flow's datagram was sent,
+/// decoded from its type/code so plugins don't have to re-derive it from
+/// the raw values.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum IcmpErrorReason {
+    DestinationUnreachable,
+    TimeExceeded,
+    /// IPv4 "Fragmentation Needed and DF was Set" / IPv6 Packet Too Big:
+    /// the classic Path-MTU-Discovery signal.
+    FragmentationNeeded,
+}
+
+/// Per-packet metadata handed to `Plugin::handle_layer_transport` (and
+/// friends), bundling the flow key, direction, and decoded payloads so
+/// plugins don't have to re-derive them.
+#[derive(Clone)]
+pub struct PacketInfo<'a> {
+    pub five_tuple: &'a FiveTuple,
+    /// `true` if this packet travels in the same direction as the first
+    /// packet seen for the flow (client -> server).
+    pub to_server: bool,
+    pub l3_type: u16,
+    pub l4_data: &'a [u8],
+    pub l4_type: u8,
+    pub l4_payload: Option<&'a [u8]>,
+    pub flow: Option<&'a Flow>,
+    pub pcap_index: usize,
+    /// In-order, de-duplicated TCP byte stream for this direction, up to
+    /// and including the bytes newly delivered by this packet. `None` for
+    /// non-TCP traffic, or when this segment didn't advance the stream
+    /// (out-of-order/retransmitted data is buffered internally instead).
+    pub stream_data: Option<&'a [u8]>,
+    /// For an ICMP/ICMPv6 error, the flow the quoted inner datagram
+    /// belongs to (looked up by reparsing that datagram's own five-tuple),
+    /// and why it was sent. `None` for non-ICMP traffic, or when the
+    /// quoted datagram didn't match a tracked flow.
+    pub related_flow_id: Option<FlowID>,
+    pub icmp_error_reason: Option<IcmpErrorReason>,
+}
@@ -0,0 +1,147 @@
+//! The `Plugin` trait and the `PLUGIN_*` bitmask flags a plugin uses to
+//! declare which layers and flow-lifecycle events it wants dispatched to
+//! it.
+
+use crate::packet_info::{IcmpErrorReason, PacketInfo};
+use crate::plugin_stats::PluginStatsSummary;
+use crate::rewrite::RewrittenLayer;
+use crate::L3Info;
+use libpcap_tools::{Error, Flow, FiveTuple, Packet, ThreeTuple};
+use std::any::Any;
+use std::collections::HashMap;
+
+/// Dispatched `handle_layer_physical`.
+pub const PLUGIN_L1: u32 = 1 << 0;
+/// Dispatched `handle_layer_link`.
+pub const PLUGIN_L2: u32 = 1 << 1;
+/// Dispatched `handle_layer_network`.
+pub const PLUGIN_L3: u32 = 1 << 2;
+/// Dispatched `handle_layer_transport`.
+pub const PLUGIN_L4: u32 = 1 << 3;
+/// Dispatched `flow_created`.
+pub const PLUGIN_FLOW_NEW: u32 = 1 << 4;
+/// Dispatched `flow_destroyed`.
+pub const PLUGIN_FLOW_DEL: u32 = 1 << 5;
+/// Dispatched `flow_icmp_error`.
+pub const PLUGIN_FLOW_ICMP_ERROR: u32 = 1 << 6;
+
+/// Outcome of a single `handle_layer_*` call.
+pub enum PluginResult<'a> {
+    /// Nothing further to do.
+    None,
+    /// The callback failed; not fatal for the run, see `run_plugins_v2`.
+    Error(Error),
+    /// Re-dissect `payload` as a new link-layer frame of ethertype `u16`.
+    L2(u16, &'a [u8]),
+    /// Re-dissect `payload` as a new network-layer packet.
+    L3(L3Info, &'a [u8]),
+    /// Re-dissect `payload` as a new transport-layer payload for `FiveTuple`.
+    L4(FiveTuple, &'a [u8]),
+    /// An edited header the analyzer should re-serialize (recomputing length
+    /// and checksum fields) and append to the rewrite output sink, instead
+    /// of redissecting anything.
+    Rewrite(RewrittenLayer),
+}
+
+/// Per-dispatch-call scratch space shared by every plugin invoked for a
+/// given layer (see `run_plugins_v2`): a plugin that depends on another
+/// (via `Plugin::dependencies`) is guaranteed to run after it, so it can
+/// `get` here whatever the upstream plugin `put`, rather than recomputing
+/// it -- e.g. a TLS-fingerprint plugin reading the reassembled stream a
+/// TCP-reassembly plugin already published.
+///
+/// Values are keyed by name (by convention, the publishing plugin's own
+/// `Plugin::name()`) and their concrete type; `get::<T>` only returns a
+/// value if both the key and `T` match what was `put`.
+#[derive(Default)]
+pub struct PluginContext {
+    values: HashMap<&'static str, Box<dyn Any + Send>>,
+}
+
+impl PluginContext {
+    pub fn put<T: Any + Send>(&mut self, key: &'static str, value: T) {
+        self.values.insert(key, Box::new(value));
+    }
+
+    pub fn get<T: Any + Send>(&self, key: &str) -> Option<&T> {
+        self.values.get(key)?.downcast_ref::<T>()
+    }
+}
+
+/// Implemented by every analysis plugin, whether it runs in-process or (via
+/// `ExternalPluginProxy`) out-of-process. All methods have a default no-op
+/// implementation, so a plugin only needs to override the ones matching the
+/// layers/events it declared in `plugin_type()`.
+pub trait Plugin: Send {
+    /// Bitmask of `PLUGIN_*` flags declaring which layers/events this
+    /// plugin wants dispatched to it.
+    fn plugin_type(&self) -> u32;
+
+    /// Stable identifier for this plugin, used both as a `dependencies()`
+    /// target and as the conventional `PluginContext` key for values it
+    /// publishes. Unnamed (the default) is fine for a plugin nothing else
+    /// depends on.
+    fn name(&self) -> &str {
+        ""
+    }
+
+    /// Names (see `name()`) of plugins that must run, and have a chance to
+    /// publish into the shared `PluginContext`, before this one does.
+    /// `PluginRegistry::finalize` topologically sorts on these and errors
+    /// out on an unknown name or a dependency cycle.
+    fn dependencies(&self) -> &[&'static str] {
+        &[]
+    }
+
+    fn pre_process(&mut self) {}
+    fn post_process(&mut self) {}
+
+    fn flow_created(&mut self, _flow: &Flow) {}
+    fn flow_destroyed(&mut self, _flow: &Flow) {}
+    fn flow_icmp_error(&mut self, _flow: &Flow, _reason: IcmpErrorReason) {}
+
+    fn handle_layer_physical<'a>(
+        &mut self,
+        _packet: &Packet,
+        _data: &'a [u8],
+        _pctx: &mut PluginContext,
+    ) -> PluginResult<'a> {
+        PluginResult::None
+    }
+
+    fn handle_layer_link<'a>(
+        &mut self,
+        _packet: &Packet,
+        _linktype: u16,
+        _data: &'a [u8],
+        _pctx: &mut PluginContext,
+    ) -> PluginResult<'a> {
+        PluginResult::None
+    }
+
+    fn handle_layer_network<'a>(
+        &mut self,
+        _packet: &Packet,
+        _data: &'a [u8],
+        _three_tuple: &ThreeTuple,
+        _l4_proto: u8,
+        _pctx: &mut PluginContext,
+    ) -> PluginResult<'a> {
+        PluginResult::None
+    }
+
+    fn handle_layer_transport<'a>(
+        &mut self,
+        _packet: &Packet,
+        _pinfo: &PacketInfo<'a>,
+        _pctx: &mut PluginContext,
+    ) -> PluginResult<'a> {
+        PluginResult::None
+    }
+
+    /// Fired once at `teardown`, after every packet has been processed, if
+    /// `plugin_stats_enabled` is set: lets a reporting plugin render its own
+    /// view of `PluginStatsTable::snapshot()` instead of (or in addition to)
+    /// the log report `teardown` already emits.
+    fn stats_ready(&mut self, _stats: &[PluginStatsSummary]) {}
+}
@@ -0,0 +1,179 @@
+//! Registry of active plugins: bucketed by `(layer, protocol filter)` for
+//! `run_plugins_v2`'s dispatch, and flat for the `run_plugins` events
+//! (`pre_process`, `post_process`, `flow_created`, `flow_destroyed`,
+//! `flow_icmp_error`). `finalize()` topologically sorts on `Plugin::
+//! dependencies()` so dependents always run after the plugins they read
+//! from (via `PluginContext`) in either dispatch path.
+
+use crate::plugin::{Plugin, PLUGIN_L1, PLUGIN_L2, PLUGIN_L3, PLUGIN_L4};
+use crate::plugin_stats::{PluginID, PluginOutcome, PluginStatsSummary, PluginStatsTable};
+use libpcap_tools::{Config, Error};
+use std::collections::{HashMap, VecDeque};
+use std::ops::DerefMut;
+use std::sync::{Arc, Mutex};
+use std::time::Instant;
+
+/// A plugin behind the same lock/ownership every caller (an in-process
+/// plugin, or `ExternalPluginProxy` wrapping an out-of-process one) uses.
+pub type SafePlugin = Arc<Mutex<dyn Plugin>>;
+
+/// `add_plugin`'s own copy of the bits of a plugin needed to build the
+/// dependency DAG, so `finalize()` doesn't need to re-lock every plugin
+/// (and can run after plugins have started being dispatched to).
+struct PluginMeta {
+    name: String,
+    dependencies: Vec<&'static str>,
+}
+
+/// Holds every registered plugin and dispatches to it.
+pub struct PluginRegistry {
+    /// All registered plugins, in registration order; a plugin's position
+    /// here is also its `PluginID`.
+    plugins: Vec<SafePlugin>,
+    meta: Vec<PluginMeta>,
+    /// `(layer, filter)` -> the plugins interested in it, `filter == 0`
+    /// being the catch-all bucket consulted when no more specific filter
+    /// matches (see `run_plugins_v2` in `analyzer.rs`). Kept sorted to
+    /// match `order` by `finalize()`.
+    by_layer: HashMap<(u8, u16), Vec<(PluginID, SafePlugin)>>,
+    /// Dispatch order: registration order until `finalize()` replaces it
+    /// with the topological order derived from `Plugin::dependencies()`.
+    order: Vec<PluginID>,
+    stats: PluginStatsTable,
+}
+
+impl PluginRegistry {
+    pub fn new(config: &Config) -> Self {
+        PluginRegistry {
+            plugins: Vec::new(),
+            meta: Vec::new(),
+            by_layer: HashMap::new(),
+            order: Vec::new(),
+            stats: PluginStatsTable::new(config),
+        }
+    }
+
+    /// Register `plugin`, bucketing it (under the catch-all `filter == 0`
+    /// slot) for every layer its `plugin_type()` declares. Call
+    /// `finalize()` once every plugin has been added, to order dispatch by
+    /// `Plugin::dependencies()` instead of registration order.
+    pub fn add_plugin(&mut self, plugin: SafePlugin) -> PluginID {
+        let id = PluginID(self.plugins.len());
+        let (plugin_type, name, dependencies) = {
+            let p = plugin.lock().expect("locking plugin failed (recursion ?)");
+            (p.plugin_type(), p.name().to_string(), p.dependencies().to_vec())
+        };
+        self.stats
+            .register(id, if name.is_empty() { format!("plugin#{}", id.0) } else { name.clone() });
+        self.meta.push(PluginMeta { name, dependencies });
+        for (bit, layer) in [(PLUGIN_L1, 1u8), (PLUGIN_L2, 2), (PLUGIN_L3, 3), (PLUGIN_L4, 4)] {
+            if plugin_type & bit != 0 {
+                self.by_layer
+                    .entry((layer, 0))
+                    .or_default()
+                    .push((id, plugin.clone()));
+            }
+        }
+        self.plugins.push(plugin);
+        self.order.push(id);
+        id
+    }
+
+    /// Topologically sort registered plugins on `Plugin::dependencies()`
+    /// (Kahn's algorithm), so `run_plugins`/`run_plugins_v2` dispatch
+    /// dependents after whatever they depend on. Errors if a dependency
+    /// names a plugin that was never registered, or if the dependencies
+    /// form a cycle; the previous (registration) order is left in place in
+    /// that case.
+    pub fn finalize(&mut self) -> Result<(), Error> {
+        let name_to_id: HashMap<&str, PluginID> = self
+            .meta
+            .iter()
+            .enumerate()
+            .filter(|(_, m)| !m.name.is_empty())
+            .map(|(i, m)| (m.name.as_str(), PluginID(i)))
+            .collect();
+
+        let n = self.meta.len();
+        let mut indegree = vec![0usize; n];
+        let mut dependents: Vec<Vec<usize>> = vec![Vec::new(); n];
+        for (i, meta) in self.meta.iter().enumerate() {
+            for dep_name in &meta.dependencies {
+                let dep_id = *name_to_id
+                    .get(dep_name)
+                    .ok_or(Error::Generic("plugin depends on an unregistered plugin name"))?;
+                dependents[dep_id.0].push(i);
+                indegree[i] += 1;
+            }
+        }
+
+        let mut queue: VecDeque<usize> = (0..n).filter(|&i| indegree[i] == 0).collect();
+        let mut order = Vec::with_capacity(n);
+        while let Some(i) = queue.pop_front() {
+            order.push(PluginID(i));
+            for &j in &dependents[i] {
+                indegree[j] -= 1;
+                if indegree[j] == 0 {
+                    queue.push_back(j);
+                }
+            }
+        }
+        if order.len() != n {
+            return Err(Error::Generic("plugin dependency graph has a cycle"));
+        }
+
+        let position: HashMap<PluginID, usize> =
+            order.iter().enumerate().map(|(pos, id)| (*id, pos)).collect();
+        for bucket in self.by_layer.values_mut() {
+            bucket.sort_by_key(|(id, _)| position[id]);
+        }
+        self.order = order;
+        Ok(())
+    }
+
+    pub fn get_plugins_for_layer(&self, layer: u8, filter: u16) -> Option<&Vec<(PluginID, SafePlugin)>> {
+        self.by_layer.get(&(layer, filter))
+    }
+
+    /// Run `action` on every registered plugin matching `filter_plugin`, in
+    /// dependency order (registration order until `finalize()` has run).
+    /// `kind` names the event for the stats table (`"pre_process"`,
+    /// `"flow_created"`, ...).
+    pub fn run_plugins<FT, FN>(&self, kind: &'static str, filter_plugin: FT, mut action: FN)
+    where
+        FT: Fn(&dyn Plugin) -> bool,
+        FN: FnMut(&mut dyn Plugin),
+    {
+        for id in &self.order {
+            let plugin = &self.plugins[id.0];
+            let mut p = plugin.lock().expect("locking plugin failed (recursion ?)");
+            if filter_plugin(p.deref_mut()) {
+                let start = self.stats.start();
+                action(p.deref_mut());
+                self.stats.record(*id, start, kind, PluginOutcome::Event);
+            }
+        }
+    }
+
+    pub fn stats_enabled(&self) -> bool {
+        self.stats.enabled()
+    }
+
+    pub fn stats_start(&self) -> Option<Instant> {
+        self.stats.start()
+    }
+
+    pub fn stats_record(&self, id: PluginID, start: Option<Instant>, kind: &'static str, outcome: PluginOutcome) {
+        self.stats.record(id, start, kind, outcome);
+    }
+
+    pub fn stats_snapshot(&self) -> Vec<PluginStatsSummary> {
+        self.stats.snapshot()
+    }
+
+    /// Rendered once at `teardown` (empty when `plugin_stats_enabled` is
+    /// off).
+    pub fn stats_report(&self) -> String {
+        self.stats.report()
+    }
+}
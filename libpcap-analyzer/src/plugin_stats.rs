@@ -0,0 +1,194 @@
+//! Per-plugin call counts, timing and outcome tallies, gated behind the
+//! `plugin_stats_enabled` config flag so the hot dispatch paths
+//! (`run_plugins_v2`, `PluginRegistry::run_plugins`) pay nothing when it's
+//! off.
+
+use libpcap_tools::Config;
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+/// Stable index assigned to a plugin when it's registered via
+/// `PluginRegistry::add_plugin`; used as the stats table key instead of the
+/// plugin's name, since two registered plugins can share a name.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash, PartialOrd, Ord)]
+pub struct PluginID(pub usize);
+
+/// Outcome of one instrumented call, tallied separately from the plain
+/// call count/timing so a report can surface e.g. per-layer error rates.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Hash)]
+pub enum PluginOutcome {
+    None,
+    Error,
+    L2,
+    L3,
+    L4,
+    Rewrite,
+    /// A flow-lifecycle/pre_process/post_process callback: these have no
+    /// `PluginResult` to tally, so they're only ever `Event`.
+    Event,
+}
+
+#[derive(Default)]
+struct KindStats {
+    calls: u64,
+    total_time: Duration,
+    outcomes: HashMap<PluginOutcome, u64>,
+}
+
+#[derive(Default)]
+struct PluginEntry {
+    name: String,
+    /// Keyed by dispatch kind: `"physical"`/`"link"`/`"network"`/
+    /// `"transport"` for `run_plugins_v2`, or the event name
+    /// (`"pre_process"`, `"flow_created"`, ...) for `PluginRegistry::run_plugins`.
+    by_kind: HashMap<&'static str, KindStats>,
+}
+
+/// Shared table of per-plugin instrumentation, owned by the `PluginRegistry`.
+pub struct PluginStatsTable {
+    enabled: bool,
+    entries: Mutex<HashMap<PluginID, PluginEntry>>,
+}
+
+impl PluginStatsTable {
+    /// Reads `plugin_stats_enabled` (default `false`) from `config`.
+    pub fn new(config: &Config) -> Self {
+        PluginStatsTable {
+            enabled: config.get_bool("plugin_stats_enabled").unwrap_or(false),
+            entries: Mutex::new(HashMap::new()),
+        }
+    }
+
+    pub fn enabled(&self) -> bool {
+        self.enabled
+    }
+
+    /// Start timing a call; returns `None` (and times nothing) when stats
+    /// are disabled, so callers can do
+    /// `let t = stats.start(); ...; stats.record(id, "kind", t, outcome);`
+    /// unconditionally without branching at every call site.
+    pub fn start(&self) -> Option<Instant> {
+        if self.enabled {
+            Some(Instant::now())
+        } else {
+            None
+        }
+    }
+
+    pub fn register(&self, id: PluginID, name: String) {
+        if !self.enabled {
+            return;
+        }
+        let mut entries = self.entries.lock().expect("plugin stats lock poisoned");
+        entries.insert(
+            id,
+            PluginEntry {
+                name,
+                by_kind: HashMap::new(),
+            },
+        );
+    }
+
+    pub fn record(&self, id: PluginID, start: Option<Instant>, kind: &'static str, outcome: PluginOutcome) {
+        let start = match start {
+            Some(s) => s,
+            None => return,
+        };
+        let elapsed = start.elapsed();
+        let mut entries = self.entries.lock().expect("plugin stats lock poisoned");
+        if let Some(entry) = entries.get_mut(&id) {
+            let kind_stats = entry.by_kind.entry(kind).or_default();
+            kind_stats.calls += 1;
+            kind_stats.total_time += elapsed;
+            *kind_stats.outcomes.entry(outcome).or_insert(0) += 1;
+        }
+    }
+
+    /// A point-in-time snapshot, decoupled from the live table's lock, safe
+    /// to hand to plugins via `Plugin::stats_ready` or print in a report.
+    pub fn snapshot(&self) -> Vec<PluginStatsSummary> {
+        let entries = self.entries.lock().expect("plugin stats lock poisoned");
+        let mut summaries: Vec<PluginStatsSummary> = entries
+            .iter()
+            .map(|(id, entry)| {
+                let calls = entry.by_kind.values().map(|k| k.calls).sum();
+                let total_time = entry.by_kind.values().map(|k| k.total_time).sum();
+                let errors = entry
+                    .by_kind
+                    .values()
+                    .map(|k| *k.outcomes.get(&PluginOutcome::Error).unwrap_or(&0))
+                    .sum();
+                PluginStatsSummary {
+                    id: *id,
+                    name: entry.name.clone(),
+                    calls,
+                    total_time,
+                    errors,
+                }
+            })
+            .collect();
+        summaries.sort_by_key(|s| s.id);
+        summaries
+    }
+
+    /// Human-readable report for `teardown`: plugins ranked by total time,
+    /// then by call count, with the per-plugin error rate.
+    pub fn report(&self) -> String {
+        let mut by_time = self.snapshot();
+        if by_time.is_empty() {
+            return String::new();
+        }
+        by_time.sort_by(|a, b| b.total_time.cmp(&a.total_time));
+
+        let mut out = String::from("plugin stats (by total time):\n");
+        for s in &by_time {
+            out.push_str(&format!(
+                "  {:<24} calls={:<8} total={:>9.3}ms avg={:>7.3}ms errors={} ({:.1}%)\n",
+                s.name,
+                s.calls,
+                s.total_time.as_secs_f64() * 1000.0,
+                s.avg_ms(),
+                s.errors,
+                s.error_rate_pct(),
+            ));
+        }
+
+        let mut by_calls = by_time;
+        by_calls.sort_by(|a, b| b.calls.cmp(&a.calls));
+        out.push_str("plugin stats (by call count):\n");
+        for s in &by_calls {
+            out.push_str(&format!("  {:<24} calls={}\n", s.name, s.calls));
+        }
+        out
+    }
+}
+
+/// Per-plugin summary handed to plugins via `Plugin::stats_ready` and
+/// printed by `PluginStatsTable::report`.
+#[derive(Clone, Debug)]
+pub struct PluginStatsSummary {
+    pub id: PluginID,
+    pub name: String,
+    pub calls: u64,
+    pub total_time: Duration,
+    pub errors: u64,
+}
+
+impl PluginStatsSummary {
+    pub fn avg_ms(&self) -> f64 {
+        if self.calls == 0 {
+            0.0
+        } else {
+            self.total_time.as_secs_f64() * 1000.0 / self.calls as f64
+        }
+    }
+
+    pub fn error_rate_pct(&self) -> f64 {
+        if self.calls == 0 {
+            0.0
+        } else {
+            self.errors as f64 / self.calls as f64 * 100.0
+        }
+    }
+}
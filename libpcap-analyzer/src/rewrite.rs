@@ -0,0 +1,338 @@
+//! Header serializers for `PluginResult::Rewrite`.
+//!
+//! These mirror the `pnet_packet` header structs plugins already parse
+//! packets with, but own, editable copies of the fields instead of a
+//! borrowed view over raw bytes, in the `cookie_factory` combinator style:
+//! each `gen_*` function returns a `SerializeFn` that writes one field at a
+//! time, correctly packing the sub-byte fields (IPv4 `version`/`ihl`,
+//! flags/fragment-offset, TCP data-offset/flags) and recomputing the
+//! length and checksum fields that an edit would otherwise leave stale.
+
+use cookie_factory::bytes::{be_u16, be_u32, be_u8};
+use cookie_factory::gen_simple;
+use cookie_factory::sequence::tuple;
+use cookie_factory::SerializeFn;
+use libpcap_tools::Duration;
+use std::fs::File;
+use std::io;
+use std::io::Write;
+use std::net::{Ipv4Addr, Ipv6Addr};
+use std::path::Path;
+
+/// The IP datagram a `RewrittenLayer::Tcp`/`Udp` rewrite needs wrapped in:
+/// a bare TCP/UDP header has no IP addresses, protocol, or TTL of its own,
+/// but the rewrite output sink only ever writes complete IP datagrams
+/// (see `RewriteWriter`'s `LINKTYPE_RAW` framing).
+#[derive(Clone, Debug)]
+pub enum EnclosingIp {
+    V4(Ipv4Header),
+    V6(Ipv6Header),
+}
+
+/// An edited header (plus its already-serialized, possibly plugin-mangled
+/// payload) a plugin hands back instead of an upper-layer `PluginResult`,
+/// for the analyzer to re-serialize and write to the rewrite output sink.
+pub enum RewrittenLayer {
+    Ipv4(Ipv4Header, Vec<u8>),
+    Ipv6(Ipv6Header, Vec<u8>),
+    /// The `u32` is the pseudo-header checksum contribution of `EnclosingIp`
+    /// (see `ipv4_pseudo_header_sum`/`ipv6_pseudo_header_sum`), since a bare
+    /// TCP header has no IP addresses of its own to compute it from.
+    Tcp(EnclosingIp, TcpHeader, Vec<u8>, u32),
+    Udp(EnclosingIp, UdpHeader, Vec<u8>, u32),
+}
+
+/// Internet checksum (RFC 1071): the one's-complement sum of 16-bit words,
+/// folded and complemented. `initial` seeds the sum, so a transport
+/// checksum can start from its IP pseudo-header contribution.
+pub fn checksum16(data: &[u8], initial: u32) -> u16 {
+    let mut sum = initial;
+    let mut chunks = data.chunks_exact(2);
+    for chunk in &mut chunks {
+        sum += u32::from(u16::from_be_bytes([chunk[0], chunk[1]]));
+    }
+    if let [last] = *chunks.remainder() {
+        sum += u32::from(last) << 8;
+    }
+    while sum >> 16 != 0 {
+        sum = (sum & 0xffff) + (sum >> 16);
+    }
+    !(sum as u16)
+}
+
+/// The pseudo-header contribution an IPv4-encapsulated TCP/UDP checksum is
+/// seeded with: source/destination address, protocol, and segment length.
+pub fn ipv4_pseudo_header_sum(src: Ipv4Addr, dst: Ipv4Addr, proto: u8, segment_len: u16) -> u32 {
+    let mut buf = [0u8; 12];
+    buf[0..4].copy_from_slice(&src.octets());
+    buf[4..8].copy_from_slice(&dst.octets());
+    buf[9] = proto;
+    buf[10..12].copy_from_slice(&segment_len.to_be_bytes());
+    pseudo_header_partial_sum(&buf)
+}
+
+/// Same as `ipv4_pseudo_header_sum`, for an IPv6 enclosing datagram (RFC
+/// 8200 §8.1): 16-byte addresses, a 32-bit length, and the next-header
+/// value in the last byte of its 4-byte field.
+pub fn ipv6_pseudo_header_sum(src: Ipv6Addr, dst: Ipv6Addr, proto: u8, segment_len: u32) -> u32 {
+    let mut buf = [0u8; 40];
+    buf[0..16].copy_from_slice(&src.octets());
+    buf[16..32].copy_from_slice(&dst.octets());
+    buf[32..36].copy_from_slice(&segment_len.to_be_bytes());
+    buf[39] = proto;
+    pseudo_header_partial_sum(&buf)
+}
+
+fn pseudo_header_partial_sum(buf: &[u8]) -> u32 {
+    buf.chunks_exact(2)
+        .map(|c| u32::from(u16::from_be_bytes([c[0], c[1]])))
+        .sum()
+}
+
+#[derive(Clone, Debug)]
+pub struct Ipv4Header {
+    pub dscp_ecn: u8,
+    pub identification: u16,
+    pub flags: u8,
+    pub fragment_offset: u16,
+    pub ttl: u8,
+    pub protocol: u8,
+    pub src: Ipv4Addr,
+    pub dst: Ipv4Addr,
+}
+
+fn gen_ipv4_header<'a, 'b: 'a, W: Write + 'a>(
+    hdr: &'b Ipv4Header,
+    total_length: u16,
+    checksum: u16,
+) -> impl SerializeFn<W> + 'a {
+    let version_ihl = (4u8 << 4) | 5u8; // no options: IHL is fixed at 5 words
+    let flags_frag = (u16::from(hdr.flags) << 13) | (hdr.fragment_offset & 0x1fff);
+    tuple((
+        be_u8(version_ihl),
+        be_u8(hdr.dscp_ecn),
+        be_u16(total_length),
+        be_u16(hdr.identification),
+        be_u16(flags_frag),
+        be_u8(hdr.ttl),
+        be_u8(hdr.protocol),
+        be_u16(checksum),
+        be_u32(u32::from(hdr.src)),
+        be_u32(u32::from(hdr.dst)),
+    ))
+}
+
+/// Serialize `hdr` followed by `payload`, recomputing `total_length` and
+/// the header checksum (which an edit to any other field would otherwise
+/// leave stale).
+pub fn serialize_ipv4(hdr: &Ipv4Header, payload: &[u8]) -> Vec<u8> {
+    let total_length = (20 + payload.len()) as u16;
+    let unchecked = gen_simple(gen_ipv4_header(hdr, total_length, 0), Vec::new())
+        .expect("writing to a Vec<u8> cannot fail");
+    let checksum = checksum16(&unchecked, 0);
+    let mut out = gen_simple(gen_ipv4_header(hdr, total_length, checksum), Vec::new())
+        .expect("writing to a Vec<u8> cannot fail");
+    out.extend_from_slice(payload);
+    out
+}
+
+#[derive(Clone, Debug)]
+pub struct Ipv6Header {
+    pub traffic_class: u8,
+    pub flow_label: u32,
+    pub next_header: u8,
+    pub hop_limit: u8,
+    pub src: Ipv6Addr,
+    pub dst: Ipv6Addr,
+}
+
+fn gen_ipv6_header<'a, W: Write + 'a>(
+    hdr: &'a Ipv6Header,
+    payload_length: u16,
+) -> impl SerializeFn<W> + 'a {
+    let version_tc_fl =
+        (6u32 << 28) | (u32::from(hdr.traffic_class) << 20) | (hdr.flow_label & 0x000f_ffff);
+    tuple((
+        be_u32(version_tc_fl),
+        be_u16(payload_length),
+        be_u8(hdr.next_header),
+        be_u8(hdr.hop_limit),
+        slice_16(hdr.src.octets()),
+        slice_16(hdr.dst.octets()),
+    ))
+}
+
+/// IPv6 has no header checksum to recompute, only the payload length.
+pub fn serialize_ipv6(hdr: &Ipv6Header, payload: &[u8]) -> Vec<u8> {
+    gen_simple(
+        gen_ipv6_header(hdr, payload.len() as u16),
+        Vec::new(),
+    )
+    .map(|mut out| {
+        out.extend_from_slice(payload);
+        out
+    })
+    .expect("writing to a Vec<u8> cannot fail")
+}
+
+#[derive(Clone, Debug)]
+pub struct TcpHeader {
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub sequence: u32,
+    pub ack_number: u32,
+    pub flags: u16,
+    pub window: u16,
+    pub urgent_ptr: u16,
+}
+
+fn gen_tcp_header<'a, W: Write + 'a>(hdr: &'a TcpHeader, checksum: u16) -> impl SerializeFn<W> + 'a {
+    let data_offset_flags = (5u16 << 12) | (hdr.flags & 0x01ff); // no options: offset fixed at 5 words
+    tuple((
+        be_u16(hdr.src_port),
+        be_u16(hdr.dst_port),
+        be_u32(hdr.sequence),
+        be_u32(hdr.ack_number),
+        be_u16(data_offset_flags),
+        be_u16(hdr.window),
+        be_u16(checksum),
+        be_u16(hdr.urgent_ptr),
+    ))
+}
+
+/// Serialize `hdr` followed by `payload`, recomputing the checksum over the
+/// pseudo-header (`pseudo_header_sum`, from `ipv4_pseudo_header_sum`/
+/// `ipv6_pseudo_header_sum`), the TCP header and the payload.
+pub fn serialize_tcp(hdr: &TcpHeader, payload: &[u8], pseudo_header_sum: u32) -> Vec<u8> {
+    let unchecked = gen_simple(gen_tcp_header(hdr, 0), Vec::new())
+        .expect("writing to a Vec<u8> cannot fail");
+    let seed = pseudo_header_sum + pseudo_header_partial_sum(&unchecked);
+    let checksum = checksum16(payload, seed);
+    let mut out = gen_simple(gen_tcp_header(hdr, checksum), Vec::new())
+        .expect("writing to a Vec<u8> cannot fail");
+    out.extend_from_slice(payload);
+    out
+}
+
+#[derive(Clone, Debug)]
+pub struct UdpHeader {
+    pub src_port: u16,
+    pub dst_port: u16,
+}
+
+fn gen_udp_header<'a, W: Write + 'a>(
+    hdr: &'a UdpHeader,
+    length: u16,
+    checksum: u16,
+) -> impl SerializeFn<W> + 'a {
+    tuple((
+        be_u16(hdr.src_port),
+        be_u16(hdr.dst_port),
+        be_u16(length),
+        be_u16(checksum),
+    ))
+}
+
+/// Serialize `hdr` followed by `payload`, recomputing `length` and the
+/// checksum over the pseudo-header, the UDP header and the payload. A
+/// resulting checksum of `0` (meaning "unused") is rewritten to `0xffff`,
+/// since UDP reserves `0` to mean "no checksum was computed" (RFC 768).
+pub fn serialize_udp(hdr: &UdpHeader, payload: &[u8], pseudo_header_sum: u32) -> Vec<u8> {
+    let length = (8 + payload.len()) as u16;
+    let unchecked = gen_simple(gen_udp_header(hdr, length, 0), Vec::new())
+        .expect("writing to a Vec<u8> cannot fail");
+    let seed = pseudo_header_sum + pseudo_header_partial_sum(&unchecked);
+    let checksum = match checksum16(payload, seed) {
+        0 => 0xffff,
+        c => c,
+    };
+    let mut out = gen_simple(gen_udp_header(hdr, length, checksum), Vec::new())
+        .expect("writing to a Vec<u8> cannot fail");
+    out.extend_from_slice(payload);
+    out
+}
+
+/// `cookie_factory::combinator::slice` needs a `&[u8]`, but `Ipv6Addr`'s
+/// `octets()` hands back a by-value `[u8; 16]` with nothing to borrow from
+/// inside a combinator built from a `&'a Ipv6Header` alone; copy it into
+/// the closure instead.
+fn slice_16<W: Write>(bytes: [u8; 16]) -> impl SerializeFn<W> {
+    move |mut w: cookie_factory::WriteContext<W>| {
+        w.write_all(&bytes)?;
+        Ok(w)
+    }
+}
+
+/// Classic (non-pcapng) pcap file: a global header followed by one record
+/// per rewritten packet. This is the output sink `run_plugins_v2` writes a
+/// `PluginResult::Rewrite` to, driven by the `rewrite_output_path` config
+/// key.
+pub struct RewriteWriter {
+    file: File,
+}
+
+const PCAP_MAGIC: u32 = 0xa1b2_c3d4;
+const LINKTYPE_RAW: u32 = 101; // raw IP, no link-layer header
+
+impl RewriteWriter {
+    pub fn new<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let mut file = File::create(path)?;
+        // magic, version major/minor, thiszone, sigfigs, snaplen, linktype
+        let header = gen_simple(
+            tuple((
+                cookie_factory::bytes::le_u32(PCAP_MAGIC),
+                cookie_factory::bytes::le_u16(2),
+                cookie_factory::bytes::le_u16(4),
+                cookie_factory::bytes::le_i32(0),
+                cookie_factory::bytes::le_u32(0),
+                cookie_factory::bytes::le_u32(u32::from(u16::MAX)),
+                cookie_factory::bytes::le_u32(LINKTYPE_RAW),
+            )),
+            Vec::new(),
+        )
+        .expect("writing to a Vec<u8> cannot fail");
+        file.write_all(&header)?;
+        Ok(RewriteWriter { file })
+    }
+
+    /// Append one rewritten, fully-serialized IP datagram, timestamped `ts`.
+    pub fn write_packet(&mut self, ts: Duration, data: &[u8]) -> io::Result<()> {
+        let record_header = gen_simple(
+            tuple((
+                cookie_factory::bytes::le_u32(ts.secs as u32),
+                cookie_factory::bytes::le_u32(ts.micros as u32),
+                cookie_factory::bytes::le_u32(data.len() as u32),
+                cookie_factory::bytes::le_u32(data.len() as u32),
+            )),
+            Vec::new(),
+        )
+        .expect("writing to a Vec<u8> cannot fail");
+        self.file.write_all(&record_header)?;
+        self.file.write_all(data)
+    }
+}
+
+/// Re-serialize `layer` to bytes, wrapping a transport-layer rewrite (which
+/// has no IP header of its own) back in its `EnclosingIp` datagram so the
+/// output sink always gets a complete, checksummed packet to write.
+pub fn serialize_rewritten_layer(layer: &RewrittenLayer) -> Vec<u8> {
+    match layer {
+        RewrittenLayer::Ipv4(hdr, payload) => serialize_ipv4(hdr, payload),
+        RewrittenLayer::Ipv6(hdr, payload) => serialize_ipv6(hdr, payload),
+        RewrittenLayer::Tcp(ip, hdr, payload, pseudo_header_sum) => {
+            let segment = serialize_tcp(hdr, payload, *pseudo_header_sum);
+            wrap_in_ip(ip, &segment)
+        }
+        RewrittenLayer::Udp(ip, hdr, payload, pseudo_header_sum) => {
+            let segment = serialize_udp(hdr, payload, *pseudo_header_sum);
+            wrap_in_ip(ip, &segment)
+        }
+    }
+}
+
+fn wrap_in_ip(ip: &EnclosingIp, segment: &[u8]) -> Vec<u8> {
+    match ip {
+        EnclosingIp::V4(hdr) => serialize_ipv4(hdr, segment),
+        EnclosingIp::V6(hdr) => serialize_ipv6(hdr, segment),
+    }
+}
@@ -1,14 +1,13 @@
-use crate::packet_info::PacketInfo;
-use crate::plugin_registry::PluginRegistry;
-use libpcap_tools::{Duration, Flow, FlowID, Packet};
-use pcap_parser::data::PacketData;
-use pnet_macros_support::packet::Packet as PnetPacket;
+use libpcap_tools::{Duration, Flow, FlowID};
 use pnet_packet::tcp::{TcpFlags, TcpPacket};
-use std::cmp::Ordering;
-use std::collections::{HashMap, VecDeque};
+use std::collections::HashMap;
 use std::net::IpAddr;
 use std::num::Wrapping;
 
+/// Default per-direction cap on buffered out-of-order bytes, to bound
+/// memory usage on a stream that never fills its gaps.
+const DEFAULT_MAX_BUFFER_BYTES: usize = 1 << 20; // 1 MiB
+
 #[derive(Debug, Eq, PartialEq)]
 #[allow(dead_code)]
 pub enum TcpStatus {
@@ -31,94 +30,58 @@ impl Default for TcpStatus {
     }
 }
 
-pub struct TcpSegment {
-    pub rel_seq: Wrapping<u32>,
-    pub rel_ack: Wrapping<u32>,
-    pub flags: u16,
-    pub data: Vec<u8>,
-    pub pcap_index: usize,
+#[derive(Debug, Eq, PartialEq)]
+pub enum TcpStreamError {
+    Anomaly,
+    /// Packet received but connection has expired
+    Expired,
+    HandshakeFailed,
 }
 
+/// One side of a TCP connection: its handshake/sequence state and the
+/// reassembly bookkeeping for bytes flowing *from* this peer.
 pub struct TcpPeer {
     /// Initial Seq number (absolute)
     isn: Wrapping<u32>,
-    /// Initial Ack number (absolute)
-    ian: Wrapping<u32>,
-    /// Next Seq number
+    /// Next in-order byte offset expected from this peer (relative to isn)
     next_rel_seq: Wrapping<u32>,
-    /// Last acknowledged number
-    last_rel_ack: Wrapping<u32>,
     /// Connection state
     status: TcpStatus,
-    /// The current list of segments (ordered by rel_seq)
-    segments: VecDeque<TcpSegment>,
+    /// Segments received ahead of `next_rel_seq`, sorted by relative seq,
+    /// waiting for the gap to be filled.
+    out_of_order: Vec<(Wrapping<u32>, Vec<u8>)>,
+    /// Total bytes currently held in `out_of_order`.
+    buffered_bytes: usize,
     /// DEBUG: host address
     addr: IpAddr,
     /// DEBUG: port
     port: u16,
 }
 
-impl TcpPeer {
-    fn insert_sorted(&mut self, s: TcpSegment) {
-        // find index
-        let idx = self.segments.iter().enumerate().find_map(|(n, item)| {
-            if s.rel_seq < item.rel_seq {
-                Some(n)
-            } else {
-                None
-            }
-        });
-        match idx {
-            Some(idx) => self.segments.insert(idx, s),
-            None => self.segments.push_back(s),
-        }
-    }
-}
-
-pub struct TcpStream {
-    pub client: TcpPeer,
-    pub server: TcpPeer,
-    pub status: TcpStatus,
-    // XXX timestamp of last seen packet
-    pub last_seen_ts: Duration,
-}
-
-pub struct TcpStreamReassembly {
-    pub m: HashMap<FlowID, TcpStream>,
-
-    pub timeout: Duration,
-}
-
-impl Default for TcpStreamReassembly {
-    fn default() -> Self {
-        TcpStreamReassembly {
-            m: HashMap::new(),
-            timeout: Duration::new(120, 0),
-        }
-    }
-}
-
-#[derive(Debug, Eq, PartialEq)]
-pub enum TcpStreamError {
-    Anomaly,
-    /// Packet received but connection has expired
-    Expired,
-    HandshakeFailed,
-}
-
 impl TcpPeer {
     pub fn new(addr: &IpAddr, port: u16) -> Self {
         TcpPeer {
             isn: Wrapping(0),
-            ian: Wrapping(0),
             next_rel_seq: Wrapping(0),
-            last_rel_ack: Wrapping(0),
             status: TcpStatus::Closed,
-            segments: VecDeque::new(),
+            out_of_order: Vec::new(),
+            buffered_bytes: 0,
             addr: *addr,
             port,
         }
     }
+
+    fn release_buffers(&mut self) {
+        self.out_of_order.clear();
+        self.buffered_bytes = 0;
+    }
+}
+
+pub struct TcpStream {
+    pub client: TcpPeer,
+    pub server: TcpPeer,
+    pub status: TcpStatus,
+    pub last_seen_ts: Duration,
 }
 
 impl TcpStream {
@@ -131,16 +94,12 @@ impl TcpStream {
         }
     }
 
-    pub fn handle_new_connection<'a>(
-        &mut self,
-        tcp: &'a TcpPacket,
-        to_server: bool,
-    ) -> Result<(), TcpStreamError> {
+    pub fn handle_new_connection(&mut self, tcp: &TcpPacket, to_server: bool) -> Result<(), TcpStreamError> {
         let seq = Wrapping(tcp.get_sequence());
         let ack = Wrapping(tcp.get_acknowledgement());
         let tcp_flags = tcp.get_flags();
 
-        let (mut conn, mut rev_conn) = if to_server {
+        let (conn, rev_conn) = if to_server {
             (&mut self.client, &mut self.server)
         } else {
             (&mut self.server, &mut self.client)
@@ -150,461 +109,218 @@ impl TcpStream {
             // Client -- SYN --> Server
             TcpStatus::Closed => {
                 if tcp_flags & TcpFlags::RST != 0 {
-                    // TODO check if destination.segments must be removed
-                    // client sent a RST, this is expected
                     return Ok(());
                 }
-                // XXX check flags: SYN ?
                 if tcp_flags & TcpFlags::SYN == 0 {
-                    // not a SYN - usually happens at start of pcap if missed SYN
                     warn!("First packet of a TCP stream is not a SYN");
-                    // XXX test is ACK + data, and set established if possible ?
                     return Err(TcpStreamError::Anomaly);
                 }
                 conn.isn = seq;
                 conn.next_rel_seq = Wrapping(1);
-                rev_conn.ian = seq;
                 self.status = TcpStatus::SynSent;
                 conn.status = TcpStatus::SynSent;
                 rev_conn.status = TcpStatus::Listen;
             }
             // Server -- SYN+ACK --> Client
             TcpStatus::Listen => {
-                if tcp_flags != (TcpFlags::SYN | TcpFlags::ACK) {
-                    // XXX ?
-                }
-                // XXX if plen != 0, add plen to 1 ?
                 if ack != rev_conn.isn + Wrapping(1) {
                     warn!("NEW/SYN-ACK: ack number is wrong");
                     return Err(TcpStreamError::HandshakeFailed);
                 }
                 conn.isn = seq;
                 conn.next_rel_seq = Wrapping(1);
-                rev_conn.ian = seq;
-                rev_conn.last_rel_ack = Wrapping(1);
-
                 conn.status = TcpStatus::SynRcv;
                 self.status = TcpStatus::SynRcv;
             }
             // Client -- ACK --> Server
             TcpStatus::SynSent => {
-                if tcp_flags != TcpFlags::ACK {
-                    // XXX
-                    warn!("Not an ACK");
-                }
-                // TODO check seq, ack
                 if ack != rev_conn.isn + Wrapping(1) {
                     warn!("NEW/ACK: ack number is wrong");
                     return Err(TcpStreamError::HandshakeFailed);
                 }
                 conn.status = TcpStatus::Established;
                 rev_conn.status = TcpStatus::Established;
-                rev_conn.last_rel_ack = Wrapping(1);
                 self.status = TcpStatus::Established;
             }
+            // Retransmitted SYN+ACK (the peer hasn't seen our ACK yet): the
+            // caller also routes `SynRcv`-origin packets here (see the
+            // `origin_status` match below), so this is ordinary, replayable
+            // network traffic, not a state the handshake can't be in.
+            TcpStatus::SynRcv => {
+                if tcp_flags & (TcpFlags::SYN | TcpFlags::ACK) != (TcpFlags::SYN | TcpFlags::ACK) {
+                    warn!("SynRcv: unexpected non-SYN-ACK packet, dropping connection");
+                    // Reset both sides to Closed so the stream doesn't get
+                    // stuck re-entering this same arm (and never reaching
+                    // Established) for every subsequent packet of this flow.
+                    conn.status = TcpStatus::Closed;
+                    conn.release_buffers();
+                    rev_conn.status = TcpStatus::Closed;
+                    rev_conn.release_buffers();
+                    self.status = TcpStatus::Closed;
+                    return Err(TcpStreamError::Anomaly);
+                }
+            }
             _ => unreachable!(),
         }
         Ok(())
     }
 
-    pub fn handle_established_connection<'a>(
-        &mut self,
-        tcp: &'a TcpPacket,
-        to_server: bool,
-        pinfo: &PacketInfo,
-        registry: &PluginRegistry,
-    ) -> Result<(), TcpStreamError> {
-        let (mut origin, mut destination) = if to_server {
-            (&mut self.client, &mut self.server)
-        } else {
-            (&mut self.server, &mut self.client)
-        };
-
-        let rel_seq = Wrapping(tcp.get_sequence()) - origin.isn;
-        let rel_ack = Wrapping(tcp.get_acknowledgement()) - destination.isn;
-        let tcp_flags = tcp.get_flags();
-        let plen = tcp.payload().len();
-
-        trace!("EST: plen={}", plen);
-        debug!(
-            "    Tcp rel seq {} ack {} next seq {}",
-            rel_seq, rel_ack, origin.next_rel_seq
-        );
-
-        // TODO check if closing connection
-
-        if tcp_flags & TcpFlags::ACK == 0 {
-            warn!("EST/ packet without ACK");
-        }
-
-        let segment = TcpSegment {
-            rel_seq,
-            rel_ack,
-            flags: tcp_flags,
-            data: tcp.payload().to_vec(), // XXX data cloned here
-            pcap_index: pinfo.pcap_index,
-        };
-        queue_segment(&mut origin, segment);
-
-        debug!("  segments count: {}", origin.segments.len());
-        debug!(
-            "  PEER segments count (before ACK): {}",
-            destination.segments.len()
-        );
-
-        // TODO check for close request
-        // if tcp_flags & (TcpFlags::FIN | TcpFlags::RST) != 0 {
-        //     // XXX
-        //     warn!("Requesting end of connection");
-        //     self.handle_closing_connection(tcp, to_server);
-        // }
-
-        // TODO if there is a ACK, check & send segments on the *other* side
-        if tcp_flags & TcpFlags::ACK != 0 {
-            send_peer_segments(destination, origin, rel_ack, pinfo, registry);
-        }
-
-        // if ack > destination.next_seq {
-        //     warn!("EST/data: ack number is wrong (missed packet?)");
-        //     warn!("  expected ack 0x{:x}", destination.next_seq);
-        //     warn!("  got ack 0x{:x}", ack);
-        //     return Ok(Fragment::Incomplete);
-        // }
-        // if ack < destination.next_seq {
-        //     trace!(
-        //         "TCP: partially ACKed data (expecting up to ACK {})",
-        //         destination.next_seq.wrapping_sub(destination.isn)
-        //     );
-        // }
-
-        // origin.next_seq = origin.next_seq.wrapping_add(plen as u32);
-
-        debug!(
-            "    PEER EST rel next seq {} last_ack {}",
-            destination.next_rel_seq, destination.last_rel_ack,
-        );
-
-        Ok(())
-    }
-
-    fn handle_closing_connection(
+    /// Feed an established-connection segment into the reassembler for
+    /// its origin peer, returning the in-order bytes newly made available
+    /// by this packet (empty if it was a pure ack, filled a gap further
+    /// down the stream, or duplicated already-delivered data).
+    pub fn handle_established_connection(
         &mut self,
         tcp: &TcpPacket,
         to_server: bool,
-        pinfo: &PacketInfo,
-        registry: &PluginRegistry,
-    ) -> Result<(), TcpStreamError> {
-        let (mut origin, destination) = if to_server {
-            (&mut self.client, &mut self.server)
-        } else {
-            (&mut self.server, &mut self.client)
-        };
+        max_buffer_bytes: usize,
+    ) -> Result<Vec<u8>, TcpStreamError> {
+        let origin = if to_server { &mut self.client } else { &mut self.server };
 
-        let tcp_flags = tcp.get_flags();
         let rel_seq = Wrapping(tcp.get_sequence()) - origin.isn;
-        let rel_ack = Wrapping(tcp.get_acknowledgement()) - destination.isn;
-
-        if tcp_flags & TcpFlags::ACK != 0 {
-            debug!("ACKing segments up to {}", rel_ack);
-            send_peer_segments(destination, origin, rel_ack, pinfo, registry);
-        }
-        if tcp_flags & TcpFlags::RST != 0 {
-            // if we get a RST, check the sequence number and remove matching segments
-            debug!("RST received. rel_seq: {}", rel_seq);
-            debug!(
-                "{} remaining (undelivered) segments DESTINATION",
-                destination.segments.len()
-            );
-            for (n, s) in destination.segments.iter().enumerate() {
-                debug!("  s[{}]: rel_seq={} plen={}", n, s.rel_seq, s.data.len());
-            }
-            // remove queued segments up to rel_seq
-            destination.segments.retain(|s| s.rel_ack != rel_seq);
-            debug!(
-                "{} remaining (undelivered) segments DESTINATION after removal",
-                destination.segments.len()
-            );
-            origin.status = TcpStatus::Closed; // XXX except if ACK ?
-            return Ok(());
-        }
+        let tcp_flags = tcp.get_flags();
+        let payload = tcp.payload().to_vec();
 
-        // queue segment (even if FIN, to get correct seq numbers)
-        let rel_seq = Wrapping(tcp.get_sequence()) - origin.isn;
-        let rel_ack = Wrapping(tcp.get_acknowledgement()) - destination.isn;
-        let segment = TcpSegment {
+        trace!(
+            "EST: {}:{} rel_seq={} plen={} next_rel_seq={}",
+            origin.addr,
+            origin.port,
             rel_seq,
-            rel_ack,
-            flags: tcp_flags,
-            data: tcp.payload().to_vec(), // XXX data cloned here
-            pcap_index: pinfo.pcap_index,
-        };
-        queue_segment(&mut origin, segment);
-
-        // if tcp_flags & TcpFlags::FIN != 0 {
-        //     warn!("origin next seq was {}", origin.next_rel_seq.0);
-        //     origin.next_rel_seq += Wrapping(1);
-        // }
-
-        match origin.status {
-            TcpStatus::Established => {
-                if tcp_flags & TcpFlags::FIN == 0 {
-                    warn!("Not a FIN");
-                }
-                origin.status = TcpStatus::FinWait1;
-            }
-            _ => {
-                warn!(
-                    "Unhandled closing transition: origin host {} status {:?}",
-                    origin.addr, origin.status
-                );
-                warn!(
-                    "    dest host {} status {:?}",
-                    destination.addr, destination.status
-                );
-            }
-        }
-
-        debug!(
-            "TCP connection closing, {} remaining (undelivered) segments",
-            origin.segments.len()
+            payload.len(),
+            origin.next_rel_seq
         );
-        // DEBUG
-        for (n, s) in origin.segments.iter().enumerate() {
-            debug!("  s[{}]: plen={}", n, s.data.len());
-        }
 
-        // TODO what now?
+        let data = reassemble(origin, rel_seq, payload, max_buffer_bytes);
 
-        if origin.segments.is_empty() {
-            return Ok(());
+        if tcp_flags & (TcpFlags::FIN | TcpFlags::RST) != 0 {
+            origin.release_buffers();
         }
 
-        Ok(())
+        Ok(data)
     }
 
-    // force expiration (for ex after timeout) of this stream
     fn expire(&mut self) {
         self.client.status = TcpStatus::Closed;
+        self.client.release_buffers();
         self.server.status = TcpStatus::Closed;
+        self.server.release_buffers();
     }
 } // TcpStream
 
-fn queue_segment(peer: &mut TcpPeer, segment: TcpSegment) {
-    // only store segments with data
-    if segment.data.is_empty() && segment.flags & TcpFlags::FIN == 0 {
-        return;
-    }
-    // TODO check & merge segments
-    if let Some(s) = peer.segments.front_mut() {
-        let next_seq = s.rel_seq + Wrapping(s.data.len() as u32);
-        match segment.rel_seq.cmp(&next_seq) {
-            Ordering::Equal => {
-                // XXX do nothing, simply queue segment
-                // // simple case: merge segment
-                // trace!(
-                //     "Merging segments (seq {} and {})",
-                //     s.rel_seq,
-                //     segment.rel_seq
-                // );
-                // s.data.extend_from_slice(&segment.data);
-                // s.rel_ack = segment.rel_ack;
-                // // XXX pcap_index should be a list (and append to it)
-                // // TODO check next segment in queue to test if a hole was filled
-                // return;
-            }
-            Ordering::Greater => {
-                // we have a hole
-                warn!("Missing segment");
-            }
-            Ordering::Less => {
-                // overlap
-                warn!("Segment with overlap");
-            }
-        }
-    }
-    trace!("Pushing segment");
-    peer.insert_sorted(segment);
-}
-
-fn send_peer_segments(
+/// Insert (or immediately consume) a newly-received segment for `origin`,
+/// returning the contiguous, in-order bytes this call makes available.
+///
+/// Handles sequence-number wraparound via wrapping subtraction, trims
+/// segments that overlap data already delivered (keeping the first-seen
+/// bytes), and buffers segments that arrive ahead of a gap until it is
+/// filled — bounded by `max_buffer_bytes` to avoid unbounded growth on a
+/// stream that never fills its holes.
+fn reassemble(
     origin: &mut TcpPeer,
-    destination: &mut TcpPeer,
-    rel_ack: Wrapping<u32>,
-    pinfo: &PacketInfo,
-    registry: &PluginRegistry,
-) {
-    debug!(
-        "Trying to send segments for {}:{} up to {} (last ack: {})",
-        origin.addr, origin.port, rel_ack, origin.last_rel_ack
-    );
-    if rel_ack == origin.last_rel_ack {
-        trace!("re-acking last data, doing nothing");
-        return;
-    }
-    if rel_ack < origin.last_rel_ack {
-        warn!("ack < last_ack");
+    rel_seq: Wrapping<u32>,
+    mut data: Vec<u8>,
+    max_buffer_bytes: usize,
+) -> Vec<u8> {
+    if data.is_empty() {
+        return Vec::new();
     }
 
-    // DEBUG
-    for (n, s) in origin.segments.iter().enumerate() {
-        debug!("  s[{}]: rel_seq={} plen={}", n, s.rel_seq, s.data.len());
+    let end = rel_seq + Wrapping(data.len() as u32);
+    // fully-old / retransmitted segment: end already delivered
+    if (end - origin.next_rel_seq).0 as i32 <= 0 {
+        trace!("TCP: dropping fully-retransmitted segment");
+        return Vec::new();
     }
 
-    // TODO check consistency of segment ACK numbers + order and/or missing fragments and/or overlap
-
-    #[allow(clippy::while_let_loop)]
-    loop {
-        if let Some(segment) = origin.segments.front() {
-            debug!(
-                "segment: rel_seq={}  len={}",
-                segment.rel_seq,
-                segment.data.len()
-            );
-            debug!(
-                "  origin.next_rel_seq {} ack {}",
-                origin.next_rel_seq, rel_ack
-            );
-            if origin.next_rel_seq > rel_ack {
-                warn!("next_seq > ack - partial ACK ?");
-                break;
-            }
-            if rel_ack == segment.rel_seq {
-                trace!("got a segment, not yet acked: not sending");
-                break;
-            }
-        } else {
-            // warn!("No data segment");
-            break;
-        }
-
-        let mut segment = match origin.segments.pop_front() {
-            Some(s) => s,
-            None => return,
-        };
-
-        if rel_ack < segment.rel_seq {
-            warn!("TCP ACK of unseen segment");
-            continue;
-        }
+    let mut rel_seq = rel_seq;
+    // partial overlap at the front: keep only the first-seen bytes
+    if (rel_seq - origin.next_rel_seq).0 as i32 < 0 {
+        let overlap = (origin.next_rel_seq - rel_seq).0 as usize;
+        let overlap = overlap.min(data.len());
+        debug!("TCP: trimming {} overlapping bytes", overlap);
+        data.drain(0..overlap);
+        rel_seq = origin.next_rel_seq;
+    }
 
-        if rel_ack < segment.rel_seq + Wrapping(segment.data.len() as u32) {
-            // warn!("ACK lower then seq + segment size - SACK?");
-            debug!("ACK for part of buffer");
-            // split data and insert new dummy segment
-            debug!("rel_ack {} segment.rel_seq {}", rel_ack, segment.rel_seq);
-            debug!("segment data len {}", segment.data.len());
-            let remaining = segment
-                .data
-                .split_off((rel_ack - segment.rel_seq).0 as usize);
-            let new_segment = TcpSegment {
-                data: remaining,
-                rel_ack,
-                ..segment
-            };
-            debug!(
-                "insert new segment from {} len {}",
-                new_segment.rel_ack,
-                new_segment.data.len()
+    if rel_seq != origin.next_rel_seq {
+        // gap: buffer until it is filled, bounded by the per-peer cap
+        if origin.buffered_bytes + data.len() > max_buffer_bytes {
+            warn!(
+                "TCP: reassembly buffer cap reached for {}:{}, dropping out-of-order segment",
+                origin.addr, origin.port
             );
-            origin.insert_sorted(new_segment);
+            return Vec::new();
         }
-
-        send_single_segment(origin, destination, segment, pinfo, registry);
+        origin.buffered_bytes += data.len();
+        let pos = origin
+            .out_of_order
+            .iter()
+            .position(|(s, _)| (*s - rel_seq).0 as i32 > 0)
+            .unwrap_or(origin.out_of_order.len());
+        origin.out_of_order.insert(pos, (rel_seq, data));
+        return Vec::new();
     }
 
-    if origin.next_rel_seq != rel_ack {
-        // missed segments, or mayber received FIN ?
-        warn!(
-            "TCP ACKed unseen segment next_seq {} != ack {} (Missed segments?)",
-            origin.next_rel_seq, rel_ack
-        );
-        // TODO notify upper layer for missing data
+    // contiguous: deliver this segment, then drain any buffered segments
+    // that are now contiguous too
+    let mut out = data;
+    origin.next_rel_seq += Wrapping(out.len() as u32);
+    loop {
+        let idx = origin
+            .out_of_order
+            .iter()
+            .position(|(s, _)| *s == origin.next_rel_seq);
+        match idx {
+            Some(i) => {
+                let (_, d) = origin.out_of_order.remove(i);
+                origin.buffered_bytes -= d.len();
+                origin.next_rel_seq += Wrapping(d.len() as u32);
+                out.extend_from_slice(&d);
+            }
+            None => break,
+        }
     }
-
-    origin.last_rel_ack = rel_ack;
+    out
 }
 
-fn send_single_segment(
-    origin: &mut TcpPeer,
-    _destination: &mut TcpPeer,
-    segment: TcpSegment,
-    pinfo: &PacketInfo,
-    registry: &PluginRegistry,
-) {
-    trace!(
-        "Sending segment from {}:{} (plen={}, pcap_index={})",
-        origin.addr,
-        origin.port,
-        segment.data.len(),
-        segment.pcap_index,
-    );
-
-    if !segment.data.is_empty() {
-        // send ACKed segments for remote connection side
-        let five_tuple = &pinfo.five_tuple.get_reverse();
-        let to_server = !pinfo.to_server;
-        let pinfo = PacketInfo {
-            five_tuple,
-            to_server,
-            l4_payload: Some(&segment.data),
-            ..*pinfo
-        };
-
-        // XXX build a dummy packet
-        let packet = Packet {
-            interface: 0,
-            caplen: 0,
-            origlen: 0,
-            ts: Duration::new(0, 0),
-            data: PacketData::L4(pinfo.l4_type, &segment.data),
-            pcap_index: segment.pcap_index,
-        };
-        // let start = ::std::time::Instant::now();
-        registry.run_plugins_transport(pinfo.l4_type, &packet, &pinfo);
-        // let elapsed = start.elapsed();
-        // debug!("Time to run l4 plugins: {}.{}", elapsed.as_secs(), elapsed.as_millis());
-
-        origin.next_rel_seq += Wrapping(segment.data.len() as u32);
-    }
+/// Per-flow TCP stream reassembly table.
+pub struct TcpStreamReassembly {
+    pub m: HashMap<FlowID, TcpStream>,
 
-    if segment.flags & TcpFlags::FIN != 0 {
-        trace!("Segment has FIN");
-        origin.next_rel_seq += Wrapping(1);
-    }
+    pub timeout: Duration,
+    /// Per-direction cap on buffered out-of-order bytes.
+    pub max_buffer_bytes: usize,
+}
 
-    if segment.flags & TcpFlags::RST != 0 {
-        trace!("Segment has RST");
-        // origin.status = TcpStatus::FinWait1;
-        // XXX destination.status
-        // XXX stream.status
+impl Default for TcpStreamReassembly {
+    fn default() -> Self {
+        TcpStreamReassembly {
+            m: HashMap::new(),
+            timeout: Duration::new(120, 0),
+            max_buffer_bytes: DEFAULT_MAX_BUFFER_BYTES,
+        }
     }
 }
 
 impl TcpStreamReassembly {
+    /// Update reassembly state for `flow` with a newly-seen TCP segment,
+    /// returning the in-order bytes this packet makes available for its
+    /// direction (suitable for `PacketInfo::stream_data`).
     pub(crate) fn update(
         &mut self,
         flow: &Flow,
         tcp: &TcpPacket,
         to_server: bool,
-        pinfo: &PacketInfo,
-        registry: &PluginRegistry,
-    ) -> Result<(), TcpStreamError> {
+    ) -> Result<Vec<u8>, TcpStreamError> {
         trace!("5-t: {}", flow.five_tuple);
         trace!("  flow id: {:x}", flow.flow_id);
-        trace!(
-            "  seq: {:x}  ack {:x}",
-            tcp.get_sequence(),
-            tcp.get_acknowledgement()
-        );
 
-        let mut stream = self
+        let max_buffer_bytes = self.max_buffer_bytes;
+        let stream = self
             .m
             .entry(flow.flow_id)
             .or_insert_with(|| TcpStream::new(flow));
-        trace!("stream state: {:?}", stream.status);
-        trace!("to_server: {}", to_server);
 
-        // check time delay with previous packet before updating
         if flow.last_seen - stream.last_seen_ts > self.timeout {
             warn!("TCP stream received packet after timeout");
             stream.expire();
@@ -612,36 +328,23 @@ impl TcpStreamReassembly {
         }
         stream.last_seen_ts = flow.last_seen;
 
-        let (origin, _destination) = if to_server {
-            (&stream.client, &stream.server)
+        let origin_status = if to_server {
+            &stream.client.status
         } else {
-            (&stream.server, &stream.client)
+            &stream.server.status
         };
 
-        trace!(
-            "origin: {}:{} status {:?}",
-            origin.addr,
-            origin.port,
-            origin.status
-        );
         debug_print_tcp_flags(tcp.get_flags());
 
-        match origin.status {
+        match origin_status {
             TcpStatus::Closed | TcpStatus::Listen | TcpStatus::SynSent | TcpStatus::SynRcv => {
-                stream.handle_new_connection(&tcp, to_server)
+                stream.handle_new_connection(tcp, to_server)?;
+                Ok(Vec::new())
             }
-            TcpStatus::Established => {
-                // check for close request
-                if tcp.get_flags() & (TcpFlags::FIN | TcpFlags::RST) != 0 {
-                    trace!("Requesting end of connection");
-                    stream.handle_closing_connection(tcp, to_server, pinfo, registry)
-                } else {
-                    stream.handle_established_connection(tcp, to_server, pinfo, registry)
-                }
-            }
-            _ => stream.handle_closing_connection(tcp, to_server, pinfo, registry),
+            _ => stream.handle_established_connection(tcp, to_server, max_buffer_bytes),
         }
     }
+
     pub(crate) fn check_expired_connections(&mut self, now: Duration) {
         for (flow_id, stream) in self.m.iter_mut() {
             if now < stream.last_seen_ts {
@@ -661,13 +364,7 @@ impl TcpStreamReassembly {
 
 pub(crate) fn finalize_tcp_streams(analyzer: &mut crate::analyzer::Analyzer) {
     warn!("expiring all TCP connections");
-    for (flow_id, stream) in analyzer.tcp_defrag.m.iter() {
-        // TODO do we have anything to do?
-        if let Some(flow) = analyzer.flows.get_flow(*flow_id) {
-            debug!("  flow: {:?}", flow);
-        }
-    }
-    analyzer.tcp_defrag.m.clear();
+    analyzer.tcp_reassembly.m.clear();
 }
 
 fn debug_print_tcp_flags(tcp_flags: u16) {
@@ -0,0 +1,262 @@
+//! Distributes packets across one [`Analyzer`] per worker, keyed by flow so
+//! `tcp_reassembly`/`flow_map` state for a given connection always lives on
+//! a single worker.
+//!
+//! Worker selection hashes the packet's (would-be) `FiveTuple` with
+//! [`crate::toeplitz::ToeplitzHasher`]; see [`HashMode`] for why that needs
+//! to be symmetric, and `toeplitz`'s module docs for why the two approaches
+//! below achieve it.
+//!
+//! Note: packet data borrowed from the underlying pcap/pcap-ng reader isn't
+//! `'static`, so routing here is expressed as "which `Analyzer` does this
+//! packet belong to", driven synchronously from `PcapEngine::run`, rather
+//! than dispatch across real OS threads over a channel — the same scope
+//! `PcapEngine::run` itself is currently stubbed at.
+
+use crate::analyzer::Analyzer;
+use crate::output::{RecordSink, RecordWriter};
+use crate::plugin_registry::PluginRegistry;
+use crate::toeplitz::ToeplitzHasher;
+use libpcap_tools::{Config, Error, FiveTuple, Packet, ParseContext, PcapAnalyzer, SafePcapAnalyzer};
+use pcap_parser::data::PacketData;
+use pnet_packet::ethernet::{EtherTypes, EthernetPacket};
+use pnet_packet::ip::IpNextHeaderProtocols;
+use pnet_packet::ipv4::Ipv4Packet;
+use pnet_packet::ipv6::Ipv6Packet;
+use pnet_packet::tcp::TcpPacket;
+use pnet_packet::udp::UdpPacket;
+use pnet_packet::Packet as PnetPacket;
+use std::net::IpAddr;
+use std::sync::Arc;
+
+/// How `ThreadedAnalyzer` hashes a flow's tuple to pick its worker. The
+/// naive approach (hash the tuple as seen on the wire, with an arbitrary
+/// key) sends a flow's two directions to different workers, splitting its
+/// reassembly state across threads; both modes here fix that.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum HashMode {
+    /// Hash the tuple as seen on the wire, with the default (asymmetric)
+    /// RSS key. Kept for comparison/benchmarking; splits bidirectional
+    /// flows across workers.
+    Asymmetric,
+    /// Order the tuple's two endpoints before hashing (see
+    /// `toeplitz::canonicalize`), so both directions resolve to the same
+    /// tuple and therefore the same worker.
+    SymmetricCanonicalize,
+    /// Hash the tuple as seen on the wire, but with a symmetric key (see
+    /// `ToeplitzHasher::symmetric`), so both directions land on the same
+    /// worker without needing to reorder endpoints first.
+    SymmetricKey,
+}
+
+pub struct ThreadedAnalyzerBuilder {
+    num_threads: usize,
+    hash_mode: HashMode,
+    record_sink: Option<(Box<dyn RecordSink>, usize)>,
+}
+
+impl Default for ThreadedAnalyzerBuilder {
+    fn default() -> Self {
+        ThreadedAnalyzerBuilder {
+            num_threads: 1,
+            hash_mode: HashMode::SymmetricCanonicalize,
+            record_sink: None,
+        }
+    }
+}
+
+impl ThreadedAnalyzerBuilder {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn num_threads(mut self, num_threads: usize) -> Self {
+        self.num_threads = num_threads.max(1);
+        self
+    }
+
+    pub fn hash_mode(mut self, hash_mode: HashMode) -> Self {
+        self.hash_mode = hash_mode;
+        self
+    }
+
+    /// Persist destroyed flows to `sink` from a single background writer
+    /// thread, committing every `batch_size` records. Every worker built
+    /// from this builder gets a clone of the same `RecordWriter`'s
+    /// `Sender`, so flows from all workers fan into this one writer rather
+    /// than each worker needing its own store/connection.
+    pub fn record_sink(mut self, sink: Box<dyn RecordSink>, batch_size: usize) -> Self {
+        self.record_sink = Some((sink, batch_size));
+        self
+    }
+
+    /// Takes the registry by value (rather than already wrapped in an
+    /// `Arc`) specifically so `finalize()` always has the sole, unshared
+    /// reference it needs -- finalizing is not optional or best-effort
+    /// here. Callers that want `finalize()`'s "unknown dependency"/"cycle"
+    /// errors surfaced themselves can still call it before `build()`;
+    /// finalizing twice is harmless (the second call just re-sorts the
+    /// same dependency graph).
+    pub fn build(self, config: &Config, mut registry: PluginRegistry) -> ThreadedAnalyzer {
+        if let Err(e) = registry.finalize() {
+            warn!("plugin dependency graph could not be finalized: {:?}", e);
+        }
+        let registry = Arc::new(registry);
+        let hasher = match self.hash_mode {
+            HashMode::SymmetricKey => ToeplitzHasher::symmetric(*b"rs", 36),
+            HashMode::Asymmetric | HashMode::SymmetricCanonicalize => {
+                ToeplitzHasher::with_default_key()
+            }
+        };
+        let record_writer = self
+            .record_sink
+            .map(|(sink, batch_size)| RecordWriter::spawn(sink, batch_size));
+        let workers = (0..self.num_threads)
+            .map(|_| {
+                let mut worker = Analyzer::new(registry.clone(), config);
+                if let Some(writer) = record_writer.as_ref() {
+                    worker.set_record_sink(writer.sender());
+                }
+                worker
+            })
+            .collect();
+        ThreadedAnalyzer {
+            workers,
+            hasher,
+            hash_mode: self.hash_mode,
+            record_writer,
+        }
+    }
+}
+
+/// Routes packets to one `Analyzer` per worker, keeping a flow's packets on
+/// a single worker regardless of the direction they were captured in.
+pub struct ThreadedAnalyzer {
+    workers: Vec<Analyzer>,
+    hasher: ToeplitzHasher,
+    hash_mode: HashMode,
+    /// Owns the shared writer thread every worker's `Analyzer` sends
+    /// destroyed flows into; joined in `teardown` to flush whatever's
+    /// still pending and find out how many records it committed in total.
+    record_writer: Option<RecordWriter>,
+}
+
+impl ThreadedAnalyzer {
+    pub fn builder() -> ThreadedAnalyzerBuilder {
+        ThreadedAnalyzerBuilder::new()
+    }
+
+    pub fn num_workers(&self) -> usize {
+        self.workers.len()
+    }
+
+    /// Which worker owns `five_tuple`'s flow.
+    pub fn worker_for(&self, five_tuple: &FiveTuple) -> usize {
+        let h = match self.hash_mode {
+            HashMode::Asymmetric | HashMode::SymmetricKey => {
+                self.hasher.hash_five_tuple(five_tuple)
+            }
+            HashMode::SymmetricCanonicalize => self.hasher.hash_five_tuple_canonical(five_tuple),
+        };
+        (h as usize) % self.workers.len()
+    }
+}
+
+/// A quick, side-effect-free look at a packet's IP/port tuple, just to pick
+/// a worker. Only the common Ethernet/IPv4/IPv6 cases are handled; anything
+/// else (tunnels, link types `Analyzer::handle_packet` would otherwise
+/// unwrap) falls back to worker 0 rather than failing the whole capture.
+fn peek_five_tuple(packet: &Packet) -> Option<FiveTuple> {
+    match packet.data {
+        PacketData::L2(data) => {
+            let eth = EthernetPacket::new(data)?;
+            match eth.get_ethertype() {
+                EtherTypes::Ipv4 => peek_ipv4(eth.payload()),
+                EtherTypes::Ipv6 => peek_ipv6(eth.payload()),
+                _ => None,
+            }
+        }
+        PacketData::L3(ethertype, data) => match ethertype {
+            0x0800 => peek_ipv4(data),
+            0x86dd => peek_ipv6(data),
+            _ => None,
+        },
+        _ => None,
+    }
+}
+
+fn peek_ipv4(data: &[u8]) -> Option<FiveTuple> {
+    let ip = Ipv4Packet::new(data)?;
+    let proto = ip.get_next_level_protocol();
+    let (src_port, dst_port) = peek_ports(proto.0, ip.payload());
+    Some(FiveTuple {
+        src: IpAddr::V4(ip.get_source()),
+        dst: IpAddr::V4(ip.get_destination()),
+        src_port,
+        dst_port,
+        proto: proto.0,
+        vlan_tags: Vec::new(),
+        vni: None,
+    })
+}
+
+fn peek_ipv6(data: &[u8]) -> Option<FiveTuple> {
+    let ip = Ipv6Packet::new(data)?;
+    let proto = ip.get_next_header();
+    let (src_port, dst_port) = peek_ports(proto.0, ip.payload());
+    Some(FiveTuple {
+        src: IpAddr::V6(ip.get_source()),
+        dst: IpAddr::V6(ip.get_destination()),
+        src_port,
+        dst_port,
+        proto: proto.0,
+        vlan_tags: Vec::new(),
+        vni: None,
+    })
+}
+
+fn peek_ports(proto: u8, data: &[u8]) -> (u16, u16) {
+    if proto == IpNextHeaderProtocols::Tcp.0 {
+        TcpPacket::new(data)
+            .map(|p| (p.get_source(), p.get_destination()))
+            .unwrap_or((0, 0))
+    } else if proto == IpNextHeaderProtocols::Udp.0 {
+        UdpPacket::new(data)
+            .map(|p| (p.get_source(), p.get_destination()))
+            .unwrap_or((0, 0))
+    } else {
+        (0, 0)
+    }
+}
+
+impl PcapAnalyzer for ThreadedAnalyzer {
+    fn init(&mut self) -> Result<(), Error> {
+        for worker in &mut self.workers {
+            worker.init()?;
+        }
+        Ok(())
+    }
+
+    fn handle_packet(&mut self, packet: &Packet, ctx: &ParseContext) -> Result<(), Error> {
+        let idx = peek_five_tuple(packet)
+            .map(|five_tuple| self.worker_for(&five_tuple))
+            .unwrap_or(0);
+        self.workers[idx].handle_packet(packet, ctx)
+    }
+
+    fn teardown(&mut self) {
+        for worker in &mut self.workers {
+            worker.teardown();
+            // Drop this worker's `Sender` clone so the shared writer's
+            // channel actually closes once every worker has done the same,
+            // instead of `join` below blocking on `recv` forever.
+            worker.close_record_sink();
+        }
+        if let Some(writer) = self.record_writer.take() {
+            let total = writer.join();
+            info!("record writer: {} records committed", total);
+        }
+    }
+}
+
+impl SafePcapAnalyzer for ThreadedAnalyzer {}
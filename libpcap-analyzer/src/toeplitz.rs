@@ -0,0 +1,160 @@
+//! Toeplitz hash (the algorithm behind Microsoft RSS) used by the threaded
+//! analyzer to pick which worker a flow's packets are routed to.
+//!
+//! A plain Toeplitz hash of the forward tuple (srcIP,srcPort,dstIP,dstPort)
+//! and the reverse tuple (dstIP,dstPort,srcIP,srcPort) land in different
+//! buckets, splitting a bidirectional flow across two workers and breaking
+//! anything (`tcp_reassembly`, `flow_map`) that assumes one thread owns a
+//! flow's whole lifetime. Two ways to fix that, both exposed here:
+//!
+//! - [`ToeplitzHasher::symmetric`]: build the key out of one repeating
+//!   16-bit word. The hash is linear over GF(2) (it's a sum of XORed key
+//!   windows selected by the input's set bits), and swapping two segments
+//!   of the input that are each a multiple of the key's period apart
+//!   doesn't change which *windows* of a constant-word key get selected,
+//!   only the order they're XORed in — and XOR is commutative, so the
+//!   result is the same either direction. The period has to divide every
+//!   gap being swapped: `five_tuple_bytes` puts ports 16 bits apart (and
+//!   addresses 32/128 bits apart), so a 16-bit word is the longest period
+//!   that stays invariant for both; a 32-bit word (the obvious-looking
+//!   choice, since it matches the address gap) is *not* invariant across
+//!   the 16-bit port gap and silently splits bidirectional flows whenever
+//!   `src_port != dst_port`.
+//! - [`canonicalize`]: order the two endpoints (by numeric IP, then port) so
+//!   the same physical connection always hashes the same 4-tuple regardless
+//!   of which direction a given packet travels in, then hash with any key
+//!   (symmetric or not).
+
+use libpcap_tools::FiveTuple;
+use std::net::IpAddr;
+
+/// The 40-byte default Microsoft RSS Toeplitz key. Asymmetric: forward and
+/// reverse tuples of the same flow hash to different values with this key.
+pub const DEFAULT_KEY: [u8; 40] = [
+    0x6d, 0x5a, 0x56, 0xda, 0x25, 0x5b, 0x0e, 0xc2, 0x41, 0x67, 0x25, 0x3d, 0x43, 0xa3, 0x8f, 0xb0,
+    0xd0, 0xca, 0x2b, 0xcb, 0xae, 0x7b, 0x30, 0xb4, 0x77, 0xcb, 0x2d, 0xa3, 0x80, 0x30, 0xf2, 0x0c,
+    0x6a, 0x42, 0xb7, 0x3b, 0xbe, 0xac, 0x01, 0xfa,
+];
+
+pub struct ToeplitzHasher {
+    key: Vec<u8>,
+}
+
+impl ToeplitzHasher {
+    pub fn new(key: Vec<u8>) -> Self {
+        ToeplitzHasher { key }
+    }
+
+    pub fn with_default_key() -> Self {
+        Self::new(DEFAULT_KEY.to_vec())
+    }
+
+    /// A key made of `word` repeated to cover `input_len` bytes of hash
+    /// input (plus the 4 bytes of sliding-window lookahead every Toeplitz
+    /// key needs past its last input byte): `hash(a, b) == hash(b, a)` for
+    /// two byte strings swapped in the input, as long as they're separated
+    /// by a multiple of 16 bits (true of every gap `five_tuple_bytes`
+    /// produces between a forward and reverse tuple's fields).
+    pub fn symmetric(word: [u8; 2], input_len: usize) -> Self {
+        let key = word.iter().cycle().take(input_len + 4).copied().collect();
+        ToeplitzHasher { key }
+    }
+
+    /// RSS Toeplitz hash of `input`: for each set bit of `input`, XOR in the
+    /// 32-bit window of `key` starting at that bit's offset.
+    pub fn hash(&self, input: &[u8]) -> u32 {
+        let mut result: u32 = 0;
+        for (byte_idx, &byte) in input.iter().enumerate() {
+            for bit in 0..8 {
+                if byte & (0x80 >> bit) != 0 {
+                    result ^= self.key_window(byte_idx * 8 + bit);
+                }
+            }
+        }
+        result
+    }
+
+    /// The 32 bits of `key` starting at bit offset `offset`, treating `key`
+    /// as one contiguous bitstream (crossing byte boundaries as needed).
+    /// Bits past the end of `key` are treated as zero.
+    fn key_window(&self, offset: usize) -> u32 {
+        let mut window: u32 = 0;
+        for i in 0..32 {
+            let bit_pos = offset + i;
+            let byte_idx = bit_pos / 8;
+            let bit_idx = bit_pos % 8;
+            let bit = self
+                .key
+                .get(byte_idx)
+                .map(|b| (b >> (7 - bit_idx)) & 1)
+                .unwrap_or(0);
+            window = (window << 1) | u32::from(bit);
+        }
+        window
+    }
+}
+
+/// Hash input bytes for `five_tuple`: source address, destination address,
+/// source port, destination port, in that order, addresses in network byte
+/// order.
+fn five_tuple_bytes(src: IpAddr, src_port: u16, dst: IpAddr, dst_port: u16) -> Vec<u8> {
+    let mut buf = Vec::new();
+    push_addr(&mut buf, src);
+    push_addr(&mut buf, dst);
+    buf.extend_from_slice(&src_port.to_be_bytes());
+    buf.extend_from_slice(&dst_port.to_be_bytes());
+    buf
+}
+
+fn push_addr(buf: &mut Vec<u8>, addr: IpAddr) {
+    match addr {
+        IpAddr::V4(v4) => buf.extend_from_slice(&v4.octets()),
+        IpAddr::V6(v6) => buf.extend_from_slice(&v6.octets()),
+    }
+}
+
+/// Swap `five_tuple`'s two endpoints, if needed, so the same physical
+/// connection always presents the same 4-tuple to the hasher regardless of
+/// which direction a given packet travels in: the numerically smaller
+/// (address, port) pair always comes first.
+pub fn canonicalize(five_tuple: &FiveTuple) -> (IpAddr, u16, IpAddr, u16) {
+    let forward = (five_tuple.src, five_tuple.src_port);
+    let reverse = (five_tuple.dst, five_tuple.dst_port);
+    if addr_port_key(forward) <= addr_port_key(reverse) {
+        (five_tuple.src, five_tuple.src_port, five_tuple.dst, five_tuple.dst_port)
+    } else {
+        (five_tuple.dst, five_tuple.dst_port, five_tuple.src, five_tuple.src_port)
+    }
+}
+
+fn addr_port_key((addr, port): (IpAddr, u16)) -> ([u8; 16], u16) {
+    let mut bytes = [0u8; 16];
+    match addr {
+        IpAddr::V4(v4) => bytes[12..16].copy_from_slice(&v4.octets()),
+        IpAddr::V6(v6) => bytes.copy_from_slice(&v6.octets()),
+    }
+    (bytes, port)
+}
+
+impl ToeplitzHasher {
+    /// Hash `five_tuple` as-is (no endpoint canonicalization): with
+    /// [`ToeplitzHasher::symmetric`] this still gives the same bucket for
+    /// both directions of a flow; with any other key it generally won't.
+    pub fn hash_five_tuple(&self, five_tuple: &FiveTuple) -> u32 {
+        let input = five_tuple_bytes(
+            five_tuple.src,
+            five_tuple.src_port,
+            five_tuple.dst,
+            five_tuple.dst_port,
+        );
+        self.hash(&input)
+    }
+
+    /// Hash `five_tuple` after canonicalizing its endpoint order, so both
+    /// directions of a flow land in the same bucket regardless of the
+    /// hasher's key.
+    pub fn hash_five_tuple_canonical(&self, five_tuple: &FiveTuple) -> u32 {
+        let (src, src_port, dst, dst_port) = canonicalize(five_tuple);
+        self.hash(&five_tuple_bytes(src, src_port, dst, dst_port))
+    }
+}
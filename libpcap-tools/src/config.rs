@@ -0,0 +1,42 @@
+use std::collections::HashMap;
+use std::io::{self, BufRead, Read};
+
+/// Flat key/value configuration, loaded from a simple `key = value` text
+/// file and queried by the analyzer and its plugins.
+#[derive(Clone, Debug, Default)]
+pub struct Config {
+    values: HashMap<String, String>,
+}
+
+impl Config {
+    pub fn get(&self, key: &str) -> Option<&str> {
+        self.values.get(key).map(String::as_str)
+    }
+
+    pub fn get_bool(&self, key: &str) -> Option<bool> {
+        self.get(key).and_then(|v| v.parse().ok())
+    }
+
+    pub fn get_usize(&self, key: &str) -> Option<usize> {
+        self.get(key).and_then(|v| v.parse().ok())
+    }
+
+    pub fn set(&mut self, key: &str, value: &str) {
+        self.values.insert(key.to_string(), value.to_string());
+    }
+
+    pub fn load_config<R: Read>(&mut self, r: R) -> Result<(), io::Error> {
+        let reader = io::BufReader::new(r);
+        for line in reader.lines() {
+            let line = line?;
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if let Some((key, value)) = line.split_once('=') {
+                self.set(key.trim(), value.trim());
+            }
+        }
+        Ok(())
+    }
+}
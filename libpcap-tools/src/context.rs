@@ -0,0 +1,13 @@
+/// Context carried alongside a packet while it is being dissected
+/// (independent of the packet data itself, so it can be threaded through
+/// recursive `handle_l3`/`handle_l4` calls without borrowing the packet).
+#[derive(Clone, Debug, Default)]
+pub struct ParseContext {
+    pub pcap_index: usize,
+}
+
+impl ParseContext {
+    pub fn new(pcap_index: usize) -> Self {
+        ParseContext { pcap_index }
+    }
+}
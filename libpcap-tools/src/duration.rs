@@ -0,0 +1,37 @@
+use std::fmt;
+use std::ops::Sub;
+
+/// A simple (secs, micros) timestamp/duration pair, used for packet
+/// timestamps and relative delays.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq, PartialOrd, Ord)]
+pub struct Duration {
+    pub secs: u64,
+    pub micros: u64,
+}
+
+impl Duration {
+    pub fn new(secs: u64, micros: u64) -> Self {
+        Duration { secs, micros }
+    }
+}
+
+impl Sub for Duration {
+    type Output = Duration;
+
+    fn sub(self, rhs: Duration) -> Duration {
+        let mut secs = self.secs.saturating_sub(rhs.secs);
+        let micros = if self.micros >= rhs.micros {
+            self.micros - rhs.micros
+        } else {
+            secs = secs.saturating_sub(1);
+            1_000_000 + self.micros - rhs.micros
+        };
+        Duration { secs, micros }
+    }
+}
+
+impl fmt::Display for Duration {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}.{:06}", self.secs, self.micros)
+    }
+}
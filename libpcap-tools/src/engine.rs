@@ -0,0 +1,68 @@
+use crate::live_capture::LiveCapture;
+use crate::{Error, Packet, ParseContext};
+use std::io::Read;
+
+/// Implemented by analyzers that want to be driven by a `PcapEngine`.
+pub trait PcapAnalyzer {
+    /// Called once before the first packet is parsed.
+    fn init(&mut self) -> Result<(), Error> {
+        Ok(())
+    }
+
+    /// Called for every packet read from the input.
+    fn handle_packet(&mut self, packet: &Packet, ctx: &ParseContext) -> Result<(), Error>;
+
+    /// Called once after the last packet has been processed.
+    fn teardown(&mut self) {}
+}
+
+/// Marker trait for analyzers that may be driven from multiple threads
+/// (e.g. by `ThreadedAnalyzer`).
+pub trait SafePcapAnalyzer: PcapAnalyzer + Send {}
+
+/// Drives a `PcapAnalyzer` over pcap/pcap-ng input, taking care of
+/// parsing blocks and building the per-packet `ParseContext`.
+pub struct PcapEngine {
+    analyzer: Box<dyn PcapAnalyzer>,
+}
+
+impl PcapEngine {
+    pub fn new(analyzer: Box<dyn PcapAnalyzer>) -> Self {
+        PcapEngine { analyzer }
+    }
+
+    pub fn run<R: Read>(&mut self, _input: &mut R) -> Result<(), Error> {
+        self.analyzer.init()?;
+        // actual pcap/pcap-ng block iteration lives in the `pcap_parser`
+        // integration, omitted here
+        self.analyzer.teardown();
+        Ok(())
+    }
+
+    /// Same as `run`, but reads packets live from `capture` instead of a
+    /// pcap/pcap-ng file, so the analyzer (and its plugins) run unchanged
+    /// against a network interface.
+    ///
+    /// `capture.next_packet()` returns `Ok(None)` on a mere read timeout
+    /// (see its doc comment) -- that's expected any time traffic goes quiet
+    /// for longer than the capture's configured timeout, not the end of the
+    /// capture, so it's polled again rather than ending the run. This loop
+    /// only returns once `next_packet` reports a real error (e.g. the
+    /// capture device was closed).
+    pub fn run_live(&mut self, capture: &mut LiveCapture) -> Result<(), Error> {
+        self.analyzer.init()?;
+        let result = loop {
+            match capture.next_packet() {
+                Ok(Some((packet, ctx))) => {
+                    if let Err(e) = self.analyzer.handle_packet(&packet, &ctx) {
+                        break Err(e);
+                    }
+                }
+                Ok(None) => continue,
+                Err(e) => break Err(e),
+            }
+        };
+        self.analyzer.teardown();
+        result
+    }
+}
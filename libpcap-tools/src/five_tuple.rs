@@ -0,0 +1,97 @@
+use crate::ThreeTuple;
+use std::fmt;
+use std::net::IpAddr;
+
+/// Transport-layer flow key: the two endpoints, their ports, the L4
+/// protocol, and (optionally) the stack of VLAN ids and/or the GENEVE
+/// Virtual Network Identifier the packet was seen on.
+///
+/// `vlan_tags` is empty unless the `flow_include_vlan` configuration
+/// variable is set, in which case it holds the stacked VLAN ids
+/// (outermost first) so that identical IP/port tuples on different VLANs
+/// are tracked as distinct flows.
+///
+/// `vni` is `Some` whenever the packet arrived inside a GENEVE tunnel, so
+/// identical inner IP/port tuples on different virtual networks are
+/// tracked as distinct flows, the same way `vlan_tags` keeps different
+/// VLANs apart.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct FiveTuple {
+    pub src: IpAddr,
+    pub dst: IpAddr,
+    pub src_port: u16,
+    pub dst_port: u16,
+    pub proto: u8,
+    pub vlan_tags: Vec<u16>,
+    pub vni: Option<u32>,
+}
+
+impl FiveTuple {
+    pub fn from_three_tuple(
+        three_tuple: &ThreeTuple,
+        src_port: u16,
+        dst_port: u16,
+        proto: u8,
+    ) -> Self {
+        FiveTuple {
+            src: three_tuple.src,
+            dst: three_tuple.dst,
+            src_port,
+            dst_port,
+            proto,
+            vlan_tags: Vec::new(),
+            vni: None,
+        }
+    }
+
+    /// Same as `from_three_tuple`, additionally recording the VLAN tag
+    /// stack the packet traversed (outermost tag first).
+    pub fn from_three_tuple_vlan(
+        three_tuple: &ThreeTuple,
+        src_port: u16,
+        dst_port: u16,
+        proto: u8,
+        vlan_tags: Vec<u16>,
+    ) -> Self {
+        FiveTuple {
+            src: three_tuple.src,
+            dst: three_tuple.dst,
+            src_port,
+            dst_port,
+            proto,
+            vlan_tags,
+            vni: None,
+        }
+    }
+
+    /// Build the tuple seen from the other side of the connection
+    /// (addresses and ports swapped, VLAN stack and VNI unchanged).
+    pub fn get_reverse(&self) -> FiveTuple {
+        FiveTuple {
+            src: self.dst,
+            dst: self.src,
+            src_port: self.dst_port,
+            dst_port: self.src_port,
+            proto: self.proto,
+            vlan_tags: self.vlan_tags.clone(),
+            vni: self.vni,
+        }
+    }
+}
+
+impl fmt::Display for FiveTuple {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{}:{}-{}:{} [{}]",
+            self.src, self.src_port, self.dst, self.dst_port, self.proto
+        )?;
+        if !self.vlan_tags.is_empty() {
+            write!(f, " vlan={:?}", self.vlan_tags)?;
+        }
+        if let Some(vni) = self.vni {
+            write!(f, " vni={}", vni)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,57 @@
+use crate::{Duration, FiveTuple};
+
+/// Opaque identifier for a tracked flow, stable for the lifetime of the
+/// flow (derived from its `FiveTuple`).
+pub type FlowID = u64;
+
+/// State tracked for a single bidirectional flow.
+#[derive(Clone, Debug)]
+pub struct Flow {
+    pub flow_id: FlowID,
+    pub five_tuple: FiveTuple,
+    pub first_seen: Duration,
+    pub last_seen: Duration,
+    /// Packets/bytes seen travelling towards the server (the endpoint that
+    /// sent this flow's first packet), for flow-record export.
+    pub packets_toserver: u64,
+    pub bytes_toserver: u64,
+    /// Packets/bytes seen travelling towards the client.
+    pub packets_toclient: u64,
+    pub bytes_toclient: u64,
+    /// Bitwise OR of every TCP flag byte seen on this flow (always `0` for
+    /// non-TCP flows).
+    pub tcp_flags: u8,
+}
+
+impl Flow {
+    pub fn new(five_tuple: &FiveTuple, secs: u64, micros: u64) -> Self {
+        let ts = Duration::new(secs, micros);
+        Flow {
+            flow_id: 0,
+            five_tuple: five_tuple.clone(),
+            first_seen: ts,
+            last_seen: ts,
+            packets_toserver: 0,
+            bytes_toserver: 0,
+            packets_toclient: 0,
+            bytes_toclient: 0,
+            tcp_flags: 0,
+        }
+    }
+
+    /// Account for one more packet of `bytes` bytes seen on this flow,
+    /// travelling to the server if `to_server`, with `tcp_flags` (`None`
+    /// for non-TCP traffic) OR-ed into the flow's cumulative flags.
+    pub fn record_packet(&mut self, to_server: bool, bytes: u64, tcp_flags: Option<u8>) {
+        if to_server {
+            self.packets_toserver += 1;
+            self.bytes_toserver += bytes;
+        } else {
+            self.packets_toclient += 1;
+            self.bytes_toclient += bytes;
+        }
+        if let Some(flags) = tcp_flags {
+            self.tcp_flags |= flags;
+        }
+    }
+}
@@ -0,0 +1,21 @@
+mod config;
+mod context;
+mod duration;
+mod engine;
+mod error;
+mod five_tuple;
+mod flow;
+mod live_capture;
+mod packet;
+mod three_tuple;
+
+pub use config::*;
+pub use context::*;
+pub use duration::*;
+pub use engine::*;
+pub use error::*;
+pub use five_tuple::*;
+pub use flow::*;
+pub use live_capture::*;
+pub use packet::*;
+pub use three_tuple::*;
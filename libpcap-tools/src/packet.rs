@@ -0,0 +1,20 @@
+use crate::Duration;
+use pcap_parser::data::PacketData;
+use pcap_parser::Linktype;
+
+/// A single captured packet, as handed to the analyzer by the pcap engine.
+#[derive(Debug)]
+pub struct Packet<'a> {
+    pub interface: u32,
+    pub caplen: u32,
+    pub origlen: u32,
+    pub ts: Duration,
+    pub data: PacketData<'a>,
+    /// The capture's declared link-layer type (from the pcap/pcap-ng file's
+    /// global/interface header, or set directly by a live source). `data`
+    /// being `PacketData::L2` only says "this is framed at layer 2"; this
+    /// field is what tells the analyzer which layer-2 framing it actually
+    /// is (Ethernet vs. IEEE 802.15.4, etc).
+    pub linktype: Linktype,
+    pub pcap_index: usize,
+}
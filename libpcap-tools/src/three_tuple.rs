@@ -0,0 +1,17 @@
+use std::fmt;
+use std::net::IpAddr;
+
+/// Network-layer tuple: protocol (ethertype or IP next-header, depending on
+/// caller) and the two endpoint addresses.
+#[derive(Clone, Debug, Eq, Hash, PartialEq)]
+pub struct ThreeTuple {
+    pub proto: u16,
+    pub src: IpAddr,
+    pub dst: IpAddr,
+}
+
+impl fmt::Display for ThreeTuple {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}:{}:0x{:x}", self.src, self.dst, self.proto)
+    }
+}